@@ -0,0 +1,241 @@
+use crate::apu::dsp::{CYCLES_PER_SAMPLE, Dsp};
+use crate::apu::timer::Timer;
+use crate::bus::{Address, Bus};
+
+// Boot program that receives the sound driver from the main CPU
+const IPL_ROM: [u8; 64] = [
+    0xCD, 0xEF, 0xBD, 0xE8, 0x00, 0xC6, 0x1D, 0xD0, 0xFC, 0x8F, 0xAA, 0xF4, 0x8F, 0xBB, 0xF5, 0x78,
+    0xCC, 0xF4, 0xD0, 0xFB, 0x2F, 0x19, 0xEB, 0xF4, 0xD0, 0xFC, 0x7E, 0xF4, 0xD0, 0x0B, 0xE4, 0xF5,
+    0xCB, 0xF4, 0xD7, 0x00, 0xFC, 0xD0, 0xF3, 0xAB, 0x01, 0x10, 0xEF, 0x7E, 0xF4, 0x10, 0xEB, 0xBA,
+    0xF6, 0xDA, 0x00, 0xBA, 0xF4, 0xC4, 0xF4, 0xDD, 0x5D, 0xD0, 0xDB, 0x1F, 0x00, 0x00, 0xC0, 0xFF,
+];
+
+bitfield! {
+    struct Test(pub u8) {
+        timers_disabled: bool @ 0,
+        ram_write_enabled: bool @ 1,
+        timers_enabled: bool @ 3,
+    }
+}
+
+bitfield! {
+    struct Control(pub u8) {
+        timer_enabled: u8 @ 0..=2,
+        clear_ports_01: bool @ 4,
+        clear_ports_23: bool @ 5,
+        ipl_rom_enabled: bool @ 7,
+    }
+}
+
+pub struct ApuBus {
+    pub aram: Box<[u8; 0x10000]>,
+    /// Written by the main CPU at $2140-$2143, read by the SPC700 at $F4-$F7
+    pub cpu_to_apu: [u8; 4],
+    /// Written by the SPC700 at $F4-$F7, read by the main CPU at $2140-$2143
+    pub apu_to_cpu: [u8; 4],
+    /// SPC700 cycles elapsed since power on
+    pub cycles: u64,
+    test: Test,
+    control: Control,
+    dsp_addr: u8,
+    pub dsp: Dsp,
+    // SPC700 cycles since the last DSP sample
+    dsp_cycles: u64,
+    aux_io: [u8; 2],
+    timers: [Timer; 3],
+}
+
+impl ApuBus {
+    pub fn new() -> Self {
+        Self {
+            aram: vec![0; 0x10000].into_boxed_slice().try_into().unwrap(),
+            cpu_to_apu: [0; 4],
+            apu_to_cpu: [0; 4],
+            cycles: 0,
+            test: Test(0x0A),
+            control: Control(0xB0),
+            dsp_addr: 0,
+            dsp: Dsp::new(),
+            dsp_cycles: 0,
+            aux_io: [0; 2],
+            timers: [Timer::new(128), Timer::new(128), Timer::new(16)],
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        if self.test.timers_enabled() && !self.test.timers_disabled() {
+            for timer in &mut self.timers {
+                timer.tick(cycles);
+            }
+        }
+
+        self.dsp_cycles += cycles;
+        while self.dsp_cycles >= CYCLES_PER_SAMPLE {
+            self.dsp_cycles -= CYCLES_PER_SAMPLE;
+            self.dsp.step(&mut self.aram);
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x00F0..=0x00FF => self.read_register(addr),
+            0xFFC0..=0xFFFF if self.control.ipl_rom_enabled() => {
+                IPL_ROM[usize::from(addr - 0xFFC0)]
+            },
+            _ => self.aram[usize::from(addr)],
+        }
+    }
+
+    /// Writes reach ARAM even under the registers and the IPL ROM
+    fn write(&mut self, addr: u16, data: u8) {
+        if let 0x00F0..=0x00FF = addr {
+            self.write_register(addr, data);
+        }
+        if self.test.ram_write_enabled() {
+            self.aram[usize::from(addr)] = data;
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0xF2 => self.dsp_addr,
+            0xF3 => self.dsp.read(self.dsp_addr),
+            0xF4..=0xF7 => self.cpu_to_apu[usize::from(addr - 0xF4)],
+            0xF8..=0xF9 => self.aux_io[usize::from(addr - 0xF8)],
+            0xFD..=0xFF => self.timers[usize::from(addr - 0xFD)].read_counter(),
+            // TEST, CONTROL and the timer targets are write only
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xF0 => self.test = Test(data),
+            0xF1 => self.write_control(data),
+            0xF2 => self.dsp_addr = data,
+            // $80-$FF mirror the DSP registers as read only
+            0xF3 if self.dsp_addr < 0x80 => self.dsp.write(self.dsp_addr, data),
+            0xF4..=0xF7 => self.apu_to_cpu[usize::from(addr - 0xF4)] = data,
+            0xF8..=0xF9 => self.aux_io[usize::from(addr - 0xF8)] = data,
+            0xFA..=0xFC => self.timers[usize::from(addr - 0xFA)].set_target(data),
+            _ => {},
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.control = Control(data);
+        for (idx, timer) in self.timers.iter_mut().enumerate() {
+            timer.set_enabled(self.control.timer_enabled() & (1 << idx) != 0);
+        }
+        if self.control.clear_ports_01() {
+            self.cpu_to_apu[0] = 0;
+            self.cpu_to_apu[1] = 0;
+        }
+        if self.control.clear_ports_23() {
+            self.cpu_to_apu[2] = 0;
+            self.cpu_to_apu[3] = 0;
+        }
+    }
+}
+
+impl Bus for ApuBus {
+    fn peek_at(&self, addr: Address) -> Option<u8> {
+        Some(self.aram[usize::from(addr.offset)])
+    }
+
+    fn read_and_tick(&mut self, addr: Address) -> u8 {
+        self.tick(1);
+        self.read(addr.offset)
+    }
+
+    fn write_and_tick(&mut self, addr: Address, data: u8) {
+        self.tick(1);
+        self.write(addr.offset, data);
+    }
+
+    fn add_io_cycles(&mut self, cycles: usize) {
+        self.tick(cycles as u64);
+    }
+
+    fn fired_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn fired_irq(&mut self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_hides_the_ipl_rom() {
+        let mut bus = ApuBus::new();
+        bus.write(0xFFC0, 0x12);
+        assert_eq!(bus.read(0xFFC0), 0xCD);
+
+        bus.write(0xF1, 0x00);
+        assert_eq!(bus.read(0xFFC0), 0x12);
+    }
+
+    #[test]
+    fn control_clears_the_input_ports() {
+        let mut bus = ApuBus::new();
+        bus.cpu_to_apu = [1, 2, 3, 4];
+
+        bus.write(0xF1, 0x10);
+        assert_eq!(bus.cpu_to_apu, [0, 0, 3, 4]);
+        bus.write(0xF1, 0x20);
+        assert_eq!(bus.cpu_to_apu, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ports_are_split_by_direction() {
+        let mut bus = ApuBus::new();
+        bus.cpu_to_apu[1] = 0x34;
+        bus.write(0xF5, 0x56);
+
+        assert_eq!(bus.read(0xF5), 0x34);
+        assert_eq!(bus.apu_to_cpu[1], 0x56);
+    }
+
+    #[test]
+    fn dsp_registers_above_0x7f_are_read_only_mirrors() {
+        let mut bus = ApuBus::new();
+        bus.write(0xF2, 0x0C);
+        bus.write(0xF3, 0x7F);
+        bus.write(0xF2, 0x8C);
+        bus.write(0xF3, 0x00);
+
+        assert_eq!(bus.read(0xF3), 0x7F);
+        assert_eq!(bus.read(0xF2), 0x8C);
+    }
+
+    #[test]
+    fn timers_run_from_the_spc700_clock() {
+        let mut bus = ApuBus::new();
+        bus.write(0xFA, 4);
+        bus.write(0xFC, 4);
+        bus.write(0xF1, 0x05);
+
+        bus.add_io_cycles(128 * 4);
+        assert_eq!(bus.read(0xFD), 1);
+        assert_eq!(bus.read(0xFE), 0);
+        assert_eq!(bus.read(0xFF), 8);
+        assert_eq!(bus.read(0xFF), 0);
+    }
+
+    #[test]
+    fn test_register_can_stop_the_timers() {
+        let mut bus = ApuBus::new();
+        bus.write(0xFA, 1);
+        bus.write(0xF1, 0x01);
+        bus.write(0xF0, 0x0B);
+
+        bus.add_io_cycles(128 * 4);
+        assert_eq!(bus.read(0xFD), 0);
+    }
+}
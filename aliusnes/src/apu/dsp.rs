@@ -0,0 +1,446 @@
+use crate::apu::dsp::echo::Echo;
+use crate::apu::dsp::voice::Voice;
+
+mod brr;
+mod echo;
+mod envelope;
+mod gaussian;
+mod voice;
+
+/// Native audio rate, the DSP outputs a stereo sample every 32 SPC700
+/// cycles
+pub const SAMPLE_RATE: u32 = 32_000;
+pub const CYCLES_PER_SAMPLE: u64 = 32;
+
+// Up to a second of samples is kept for the frontend, past this point
+// the oldest half is dropped
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize;
+
+// Voice registers, at $x0-$x9 for voice x
+const VOLL: usize = 0x00;
+const VOLR: usize = 0x01;
+const PITCHL: usize = 0x02;
+const PITCHH: usize = 0x03;
+const SRCN: usize = 0x04;
+const ADSR1: usize = 0x05;
+const ADSR2: usize = 0x06;
+const GAIN: usize = 0x07;
+const ENVX: usize = 0x08;
+const OUTX: usize = 0x09;
+
+const MVOLL: usize = 0x0C;
+const MVOLR: usize = 0x1C;
+const EVOLL: usize = 0x2C;
+const EVOLR: usize = 0x3C;
+const KON: usize = 0x4C;
+const KOFF: usize = 0x5C;
+const FLG: usize = 0x6C;
+const ENDX: usize = 0x7C;
+const EFB: usize = 0x0D;
+const PMON: usize = 0x2D;
+const NON: usize = 0x3D;
+const EON: usize = 0x4D;
+const DIR: usize = 0x5D;
+const ESA: usize = 0x6D;
+const EDL: usize = 0x7D;
+// Echo filter coefficients, at $x0F for tap x
+const FIR: usize = 0x0F;
+
+// Envelopes and noise step when the global counter, decremented every
+// sample, is a multiple of the rate period
+const COUNTER_RANGE: u16 = 2048 * 5 * 3;
+const COUNTER_RATES: [u16; 32] = [
+    COUNTER_RANGE + 1, // Never fires
+    2048,
+    1536,
+    1280,
+    1024,
+    768,
+    640,
+    512,
+    384,
+    320,
+    256,
+    192,
+    160,
+    128,
+    96,
+    80,
+    64,
+    48,
+    40,
+    32,
+    24,
+    20,
+    16,
+    12,
+    10,
+    8,
+    6,
+    5,
+    4,
+    3,
+    2,
+    1,
+];
+const COUNTER_OFFSETS: [u16; 32] = [
+    1, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040,
+    536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 0, 0,
+];
+
+bitfield! {
+    struct Flags(pub u8) {
+        noise_rate: u8 @ 0..=4,
+        echo_write_disabled: bool @ 5,
+        mute: bool @ 6,
+        soft_reset: bool @ 7,
+    }
+}
+
+pub struct Dsp {
+    registers: [u8; 0x80],
+    voices: [Voice; 8],
+    echo: Echo,
+    // Voices keyed on since the last sample
+    key_on: u8,
+    counter: u16,
+    noise: i16,
+    pub samples: Vec<[i16; 2]>,
+}
+
+impl Dsp {
+    pub fn new() -> Self {
+        let mut registers = [0; 0x80];
+        registers[FLG] = 0xE0;
+
+        Self {
+            registers,
+            voices: std::array::from_fn(|_| Voice::new()),
+            echo: Echo::new(),
+            key_on: 0,
+            counter: 0,
+            noise: 0x4000,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn read(&self, addr: u8) -> u8 {
+        self.registers[usize::from(addr & 0x7F)]
+    }
+
+    pub fn write(&mut self, addr: u8, data: u8) {
+        let addr = usize::from(addr & 0x7F);
+        match addr {
+            KON => self.key_on |= data,
+            // Any write acknowledges all the voices
+            ENDX => {
+                self.registers[ENDX] = 0;
+                return;
+            },
+            _ => {},
+        }
+        self.registers[addr] = data;
+    }
+
+    // Start and loop addresses are listed at DIR * $100, 4 bytes per source
+    fn source_addr(&self, aram: &[u8; 0x10000], srcn: u8, looped: bool) -> u16 {
+        let entry = u16::from(self.registers[DIR]) << 8;
+        let entry = entry.wrapping_add(u16::from(srcn) * 4 + u16::from(looped) * 2);
+        u16::from_le_bytes([
+            aram[usize::from(entry)],
+            aram[usize::from(entry.wrapping_add(1))],
+        ])
+    }
+
+    fn volume(&self, addr: usize) -> i32 {
+        i32::from(self.registers[addr] as i8)
+    }
+
+    fn step_noise(&mut self, rate: u8) {
+        if rate_fires(self.counter, rate) {
+            let feedback = (self.noise << 13) ^ (self.noise << 14);
+            self.noise = (feedback & 0x4000) ^ (self.noise >> 1);
+        }
+    }
+
+    pub fn step(&mut self, aram: &mut [u8; 0x10000]) {
+        self.counter = self.counter.checked_sub(1).unwrap_or(COUNTER_RANGE - 1);
+
+        let flags = Flags(self.registers[FLG]);
+        self.step_noise(flags.noise_rate());
+
+        let key_on = std::mem::take(&mut self.key_on);
+        let key_off = self.registers[KOFF];
+        let mut main_out = [0; 2];
+        let mut echo_out = [0; 2];
+        let mut prev_output = 0;
+
+        for idx in 0..8 {
+            let base = idx << 4;
+            let bit = 1 << idx;
+            let srcn = self.registers[base | SRCN];
+
+            if key_on & bit != 0 {
+                let start_addr = self.source_addr(aram, srcn, false);
+                self.voices[idx].key_on(start_addr);
+                self.registers[ENDX] &= !bit;
+            }
+            if flags.soft_reset() {
+                self.voices[idx].envelope.silence();
+            } else if key_off & bit != 0 {
+                self.voices[idx].envelope.release();
+            }
+
+            let mut pitch = i32::from(u16::from_le_bytes([
+                self.registers[base | PITCHL],
+                self.registers[base | PITCHH] & 0x3F,
+            ]));
+            // Voice 0 has no previous voice to be modulated by
+            if self.registers[PMON] & bit & !1 != 0 {
+                pitch += ((prev_output >> 5) * pitch) >> 10;
+            }
+
+            let noise = (self.registers[NON] & bit != 0).then_some(self.noise);
+            let voice = &self.voices[idx];
+            let output = voice.output(noise);
+            self.registers[base | ENVX] = (voice.envelope.level >> 4) as u8;
+            self.registers[base | OUTX] = (output >> 8) as u8;
+
+            let echo_enabled = self.registers[EON] & bit != 0;
+            for (channel, volume) in [VOLL, VOLR].into_iter().enumerate() {
+                let amp = (output * self.volume(base | volume)) >> 7;
+                main_out[channel] = clamp16(main_out[channel] + amp);
+                if echo_enabled {
+                    echo_out[channel] = clamp16(echo_out[channel] + amp);
+                }
+            }
+            prev_output = output;
+
+            let voice = &mut self.voices[idx];
+            if !voice.is_starting() {
+                voice.envelope.run(
+                    self.registers[base | ADSR1],
+                    self.registers[base | ADSR2],
+                    self.registers[base | GAIN],
+                    self.counter,
+                );
+            }
+
+            let pitch = pitch.clamp(0, 0x7FFF) as u16;
+            let loop_addr = self.source_addr(aram, srcn, true);
+            if self.voices[idx].advance(aram, pitch, loop_addr) {
+                self.registers[ENDX] |= bit;
+            }
+        }
+
+        let fir = std::array::from_fn(|tap| self.registers[(tap << 4) | FIR] as i8);
+        let esa = self.registers[ESA];
+        let echo_in = self.echo.read(aram, esa, self.registers[EDL], fir);
+
+        let mut output = [0; 2];
+        for channel in 0..2 {
+            let main = (main_out[channel] * self.volume([MVOLL, MVOLR][channel])) >> 7;
+            let echo = (echo_in[channel] * self.volume([EVOLL, EVOLR][channel])) >> 7;
+            if !flags.mute() {
+                output[channel] = clamp16(i32::from(main as i16) + i32::from(echo as i16)) as i16;
+            }
+
+            let feedback = (echo_in[channel] * self.volume(EFB)) >> 7;
+            echo_out[channel] = clamp16(echo_out[channel] + i32::from(feedback as i16)) & !1;
+        }
+        self.echo
+            .write(aram, esa, echo_out, !flags.echo_write_disabled());
+
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            self.samples.drain(..MAX_BUFFERED_SAMPLES / 2);
+        }
+        self.samples.push(output);
+    }
+}
+
+fn rate_fires(counter: u16, rate: u8) -> bool {
+    let rate = usize::from(rate);
+    (counter + COUNTER_OFFSETS[rate]).is_multiple_of(COUNTER_RATES[rate])
+}
+
+fn clamp16(value: i32) -> i32 {
+    value.clamp(i16::MIN.into(), i16::MAX.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A looping sample of 16 maximum values, with its directory at $0100
+    fn aram_with_sample() -> Box<[u8; 0x10000]> {
+        let mut aram: Box<[u8; 0x10000]> = vec![0; 0x10000].into_boxed_slice().try_into().unwrap();
+        aram[0x100..0x104].copy_from_slice(&[0x00, 0x02, 0x00, 0x02]);
+        aram[0x200] = 0xB3;
+        aram[0x201..0x209].fill(0x77);
+        aram
+    }
+
+    fn dsp_playing_voice_0() -> Dsp {
+        let mut dsp = Dsp::new();
+        dsp.write(FLG as u8, 0x20);
+        dsp.write(DIR as u8, 0x01);
+        dsp.write(MVOLL as u8, 0x7F);
+        dsp.write(MVOLR as u8, 0x7F);
+        dsp.write(VOLL as u8, 0x7F);
+        dsp.write(VOLR as u8, 0x40);
+        dsp.write(PITCHH as u8, 0x10);
+        dsp.write(GAIN as u8, 0x7F);
+        dsp.write(KON as u8, 0x01);
+        dsp
+    }
+
+    fn run(dsp: &mut Dsp, aram: &mut [u8; 0x10000], samples: usize) {
+        for _ in 0..samples {
+            dsp.step(aram);
+        }
+    }
+
+    #[test]
+    fn full_sample_buffer_drops_the_oldest_samples() {
+        let mut aram = aram_with_sample();
+        let mut dsp = Dsp::new();
+        dsp.samples = (0..MAX_BUFFERED_SAMPLES as i16)
+            .map(|idx| [idx, idx])
+            .collect();
+
+        run(&mut dsp, &mut aram, 1);
+        assert_eq!(dsp.samples.len(), MAX_BUFFERED_SAMPLES / 2 + 1);
+        let first = (MAX_BUFFERED_SAMPLES / 2) as i16;
+        assert_eq!(dsp.samples[0], [first, first]);
+        assert_eq!(dsp.samples.last(), Some(&[0, 0]));
+    }
+
+    #[test]
+    fn voice_plays_the_brr_sample() {
+        let mut aram = aram_with_sample();
+        let mut dsp = dsp_playing_voice_0();
+
+        run(&mut dsp, &mut aram, 16);
+
+        let [left, right] = *dsp.samples.last().unwrap();
+        assert!(left > 0x3000, "left {left:#x}");
+        assert!(right > 0x1800 && right < left, "right {right:#x}");
+        assert_eq!(dsp.read(ENVX as u8), 0x7F);
+        assert_ne!(dsp.read(OUTX as u8), 0);
+    }
+
+    #[test]
+    fn key_on_waits_5_samples_before_playing() {
+        let mut aram = aram_with_sample();
+        let mut dsp = dsp_playing_voice_0();
+
+        run(&mut dsp, &mut aram, 6);
+        assert!(dsp.samples.iter().all(|&sample| sample == [0, 0]));
+        run(&mut dsp, &mut aram, 1);
+        assert_ne!(dsp.samples.last(), Some(&[0, 0]));
+    }
+
+    #[test]
+    fn end_block_sets_endx_and_loops() {
+        let mut aram = aram_with_sample();
+        let mut dsp = dsp_playing_voice_0();
+
+        // 5 samples of delay, then 4 samples per decode
+        run(&mut dsp, &mut aram, 9);
+        assert_eq!(dsp.read(ENDX as u8), 0x01);
+
+        dsp.write(ENDX as u8, 0xFF);
+        assert_eq!(dsp.read(ENDX as u8), 0x00);
+        run(&mut dsp, &mut aram, 16);
+        assert_eq!(dsp.read(ENDX as u8), 0x01);
+        assert_eq!(dsp.read(ENVX as u8), 0x7F);
+
+        dsp.write(KON as u8, 0x01);
+        run(&mut dsp, &mut aram, 1);
+        assert_eq!(dsp.read(ENDX as u8), 0x00);
+    }
+
+    #[test]
+    fn end_block_without_loop_silences_the_voice() {
+        let mut aram = aram_with_sample();
+        aram[0x200] = 0xB1;
+        let mut dsp = dsp_playing_voice_0();
+
+        run(&mut dsp, &mut aram, 10);
+        assert_eq!(dsp.read(ENDX as u8), 0x01);
+        assert_eq!(dsp.read(ENVX as u8), 0);
+        assert_eq!(dsp.samples.last(), Some(&[0, 0]));
+    }
+
+    #[test]
+    fn key_off_releases_the_voice() {
+        let mut aram = aram_with_sample();
+        let mut dsp = dsp_playing_voice_0();
+        run(&mut dsp, &mut aram, 8);
+
+        dsp.write(KOFF as u8, 0x01);
+        run(&mut dsp, &mut aram, 2);
+        // Released by 8 each sample from 0x7F0
+        assert_eq!(dsp.read(ENVX as u8), 0x7E);
+        run(&mut dsp, &mut aram, 0x100);
+        assert_eq!(dsp.read(ENVX as u8), 0);
+    }
+
+    #[test]
+    fn noise_replaces_the_sample() {
+        let mut aram = aram_with_sample();
+        aram[0x201..0x209].fill(0);
+        let mut dsp = dsp_playing_voice_0();
+        dsp.write(NON as u8, 0x01);
+        dsp.write(FLG as u8, 0x3F);
+
+        run(&mut dsp, &mut aram, 16);
+        let samples = &dsp.samples[8..];
+        assert!(samples.iter().any(|&[left, _]| left > 0));
+        assert!(samples.iter().any(|&[left, _]| left < 0));
+    }
+
+    #[test]
+    fn pitch_modulation_follows_the_previous_voice() {
+        let mut aram = aram_with_sample();
+        let mut dsp = dsp_playing_voice_0();
+        // Voice 1 plays the same sample at half the pitch, sped up by
+        // the output of voice 0
+        dsp.write(0x13, 0x08);
+        dsp.write(0x17, 0x7F);
+        dsp.write(PMON as u8, 0x02);
+        dsp.write(KON as u8, 0x03);
+
+        // The delay decodes 3 of the 4 groups of the first block, the last
+        // one would only come on sample 13 without modulation
+        run(&mut dsp, &mut aram, 10);
+        assert_eq!(dsp.read(ENDX as u8) & 0x02, 0x00);
+        run(&mut dsp, &mut aram, 1);
+        assert_eq!(dsp.read(ENDX as u8) & 0x02, 0x02);
+    }
+
+    #[test]
+    fn echo_plays_the_voice_back_after_the_delay() {
+        let mut aram = aram_with_sample();
+        let mut dsp = dsp_playing_voice_0();
+        dsp.write(FLG as u8, 0x00);
+        dsp.write(EON as u8, 0x01);
+        dsp.write(ESA as u8, 0x80);
+        dsp.write(EDL as u8, 0x01);
+        dsp.write(EVOLL as u8, 0x7F);
+        dsp.write(0x7F, 0x7F);
+        run(&mut dsp, &mut aram, 20);
+
+        // Silence the voice and wait for the echo of its first samples
+        dsp.write(FLG as u8, 0x80);
+        run(&mut dsp, &mut aram, 1);
+        dsp.write(FLG as u8, 0x00);
+        run(&mut dsp, &mut aram, 497);
+        assert_eq!(dsp.samples.last(), Some(&[0, 0]));
+        // The first sample voice 0 played was the 7th
+        run(&mut dsp, &mut aram, 1);
+        let [left, right] = *dsp.samples.last().unwrap();
+        assert!(left > 0x1000, "left {left:#x}");
+        assert_eq!(right, 0);
+    }
+}
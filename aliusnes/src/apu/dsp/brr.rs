@@ -0,0 +1,80 @@
+bitfield! {
+    /// First byte of each 9-byte BRR block, followed by 16 4-bit samples
+    #[derive(Clone, Copy)]
+    pub struct Header(pub u8) {
+        pub end: bool @ 0,
+        pub looped: bool @ 1,
+        pub filter: u8 @ 2..=3,
+        pub shift: u8 @ 4..=7,
+    }
+}
+
+/// Decodes the 4 samples packed in `data` over the oldest ones of the ring
+/// buffer at `pos`, the filters predict from the 2 newest samples
+pub fn decode(header: Header, data: [u8; 2], buffer: &mut [i16; 12], pos: usize) {
+    let shift = header.shift();
+    let nibbles = u16::from_be_bytes(data);
+
+    for idx in 0..4 {
+        let nibble = (nibbles << (idx * 4)) as i16 >> 12;
+        let mut sample = if shift <= 12 {
+            (i32::from(nibble) << shift) >> 1
+        } else {
+            // Invalid shifts only keep the sign
+            if nibble < 0 { -2048 } else { 0 }
+        };
+
+        let p1 = i32::from(buffer[(pos + idx + 11) % 12]);
+        let p2 = i32::from(buffer[(pos + idx + 10) % 12]) >> 1;
+        match header.filter() {
+            1 => sample += (p1 >> 1) + ((-p1) >> 5),
+            2 => sample += p1 - p2 + (p2 >> 4) + ((p1 * -3) >> 6),
+            3 => sample += p1 - p2 + ((p1 * -13) >> 7) + ((p2 * 3) >> 4),
+            _ => {},
+        }
+
+        // Samples are kept 15 bits wide, dropping the top bit
+        let sample = sample.clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+        buffer[(pos + idx) % 12] = sample.wrapping_mul(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_0_shifts_the_nibbles() {
+        let mut buffer = [0; 12];
+        decode(Header(0xB0), [0x17, 0x8F], &mut buffer, 0);
+
+        assert_eq!(buffer[..4], [1 << 11, 7 << 11, -8 << 11, -1 << 11]);
+    }
+
+    #[test]
+    fn invalid_shift_keeps_the_sign() {
+        let mut buffer = [0; 12];
+        decode(Header(0xD0), [0x71, 0x80], &mut buffer, 4);
+
+        assert_eq!(buffer[4..8], [0, 0, -4096, 0]);
+    }
+
+    #[test]
+    fn filter_1_predicts_from_the_previous_sample() {
+        let mut buffer = [0; 12];
+        buffer[11] = 0x1000;
+        decode(Header(0x04), [0x00, 0x00], &mut buffer, 0);
+
+        // 15/16 of the previous sample each step
+        assert_eq!(buffer[..4], [0xF00, 0xE10, 0xD2E, 0xC5A]);
+    }
+
+    #[test]
+    fn samples_wrap_at_15_bits() {
+        let mut buffer = [0; 12];
+        buffer[11] = 0x7FFE;
+        decode(Header(0xB4), [0x70, 0x00], &mut buffer, 0);
+
+        assert_eq!(i32::from(buffer[0]), 22527 * 2 - 0x10000);
+    }
+}
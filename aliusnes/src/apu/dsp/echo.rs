@@ -0,0 +1,124 @@
+use crate::apu::dsp::clamp16;
+
+pub struct Echo {
+    // Last 8 samples read from the buffer, filtered by the FIR
+    history: [[i32; 2]; 8],
+    history_pos: usize,
+    offset: u16,
+    length: u16,
+}
+
+impl Echo {
+    pub fn new() -> Self {
+        Self {
+            history: [[0; 2]; 8],
+            history_pos: 0,
+            offset: 0,
+            length: 0,
+        }
+    }
+
+    fn addr(&self, esa: u8) -> u16 {
+        (u16::from(esa) << 8).wrapping_add(self.offset)
+    }
+
+    /// Reads the oldest sample of the echo buffer and returns the output of
+    /// the 8-tap FIR filter, FIR0 weights the oldest sample
+    pub fn read(&mut self, aram: &[u8; 0x10000], esa: u8, edl: u8, fir: [i8; 8]) -> [i32; 2] {
+        // The buffer size only changes once the current one wraps around
+        if self.offset == 0 {
+            self.length = u16::from(edl & 0x0F) << 11;
+        }
+
+        let addr = self.addr(esa);
+        self.history_pos = (self.history_pos + 1) % 8;
+        for (channel, sample) in self.history[self.history_pos].iter_mut().enumerate() {
+            let addr = addr.wrapping_add(channel as u16 * 2);
+            let data = i16::from_le_bytes([
+                aram[usize::from(addr)],
+                aram[usize::from(addr.wrapping_add(1))],
+            ]);
+            *sample = i32::from(data >> 1);
+        }
+
+        std::array::from_fn(|channel| {
+            let tap = |idx: usize| {
+                let sample = self.history[(self.history_pos + 1 + idx) % 8][channel];
+                (sample * i32::from(fir[idx])) >> 6
+            };
+            // The first 7 taps wrap around before the last one clamps
+            let sum = (0..7).map(tap).sum::<i32>() as i16;
+            clamp16(i32::from(sum) + tap(7)) & !1
+        })
+    }
+
+    /// Stores `input` in place of the sample just read and moves forward
+    pub fn write(&mut self, aram: &mut [u8; 0x10000], esa: u8, input: [i32; 2], enabled: bool) {
+        if enabled {
+            let addr = self.addr(esa);
+            for (channel, sample) in input.into_iter().enumerate() {
+                let addr = addr.wrapping_add(channel as u16 * 2);
+                let [low, high] = (sample as i16).to_le_bytes();
+                aram[usize::from(addr)] = low;
+                aram[usize::from(addr.wrapping_add(1))] = high;
+            }
+        }
+
+        self.offset += 4;
+        if self.offset >= self.length {
+            self.offset = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aram() -> Box<[u8; 0x10000]> {
+        vec![0; 0x10000].into_boxed_slice().try_into().unwrap()
+    }
+
+    #[test]
+    fn written_samples_come_back_after_the_delay() {
+        let mut aram = aram();
+        let mut echo = Echo::new();
+        let fir = [0, 0, 0, 0, 0, 0, 0, 0x40];
+
+        // EDL 1 is 2 KiB, 512 stereo samples
+        for idx in 0..512 {
+            assert_eq!(echo.read(&aram, 0x80, 1, fir), [0, 0]);
+            let input = if idx == 0 { [0x1000, -0x1000] } else { [0, 0] };
+            echo.write(&mut aram, 0x80, input, true);
+        }
+        assert_eq!(aram[0x8000..0x8004], [0x00, 0x10, 0x00, 0xF0]);
+        assert_eq!(echo.read(&aram, 0x80, 1, fir), [0x800, -0x800]);
+    }
+
+    #[test]
+    fn fir_filters_the_history() {
+        let mut aram = aram();
+        let mut echo = Echo::new();
+        aram[0x0000..0x0004].copy_from_slice(&[0x00, 0x10, 0x00, 0x10]);
+        let fir = [0x40, 0, 0, 0, 0, 0, 0, 0x20];
+
+        assert_eq!(echo.read(&aram, 0x00, 0, fir), [0x400, 0x400]);
+        echo.write(&mut aram, 0x00, [0, 0], false);
+        for _ in 0..6 {
+            echo.read(&aram, 0x00, 0, fir);
+            echo.write(&mut aram, 0x00, [0, 0], false);
+        }
+        // The first sample reached the oldest tap
+        assert_eq!(echo.read(&aram, 0x00, 0, fir), [0xC00, 0xC00]);
+    }
+
+    #[test]
+    fn disabled_writes_leave_the_buffer_alone() {
+        let mut aram = aram();
+        let mut echo = Echo::new();
+
+        echo.read(&aram, 0x10, 0, [0; 8]);
+        echo.write(&mut aram, 0x10, [0x1234, 0x1234], false);
+        assert_eq!(aram[0x1000..0x1004], [0; 4]);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::apu::dsp::rate_fires;
+
+bitfield! {
+    struct Adsr1(pub u8) {
+        attack_rate: u8 @ 0..=3,
+        decay_rate: u8 @ 4..=6,
+        enabled: bool @ 7,
+    }
+}
+
+bitfield! {
+    struct Adsr2(pub u8) {
+        sustain_rate: u8 @ 0..=4,
+        sustain_level: u8 @ 5..=7,
+    }
+}
+
+bitfield! {
+    struct Gain(pub u8) {
+        rate: u8 @ 0..=4,
+        mode: u8 @ 5..=7,
+        direct_level: u8 @ 0..=6,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+pub struct Envelope {
+    mode: Mode,
+    pub level: u16,
+    // Last computed level before clamping, bent increase checks it
+    hidden_level: i32,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Release,
+            level: 0,
+            hidden_level: 0,
+        }
+    }
+
+    pub fn key_on(&mut self) {
+        self.mode = Mode::Attack;
+        self.level = 0;
+        self.hidden_level = 0;
+    }
+
+    pub fn release(&mut self) {
+        self.mode = Mode::Release;
+    }
+
+    pub fn silence(&mut self) {
+        self.mode = Mode::Release;
+        self.level = 0;
+    }
+
+    /// Computes the next level from ADSR or GAIN, it is only stored when
+    /// the global counter hits the selected rate
+    pub fn run(&mut self, adsr1: u8, adsr2: u8, gain: u8, counter: u16) {
+        let mut level = i32::from(self.level);
+
+        // Release ignores the rates, dropping 8 every sample
+        if self.mode == Mode::Release {
+            self.level = (level - 8).max(0) as u16;
+            return;
+        }
+
+        let adsr1 = Adsr1(adsr1);
+        // Sustain level is compared against bits 5-7 of ADSR2, or of GAIN
+        // when ADSR is disabled
+        let mut sustain_data = adsr2;
+        let rate = if adsr1.enabled() {
+            if self.mode == Mode::Attack {
+                let rate = adsr1.attack_rate() * 2 + 1;
+                level += if rate < 31 { 0x20 } else { 0x400 };
+                rate
+            } else {
+                level -= 1;
+                level -= level >> 8;
+                if self.mode == Mode::Decay {
+                    adsr1.decay_rate() * 2 + 0x10
+                } else {
+                    Adsr2(adsr2).sustain_rate()
+                }
+            }
+        } else {
+            sustain_data = gain;
+            let gain = Gain(gain);
+            match gain.mode() {
+                0..=3 => {
+                    level = i32::from(gain.direct_level()) * 0x10;
+                    return self.set_level(level, sustain_data, 31, counter);
+                },
+                // Linear decrease
+                4 => level -= 0x20,
+                // Exponential decrease
+                5 => {
+                    level -= 1;
+                    level -= level >> 8;
+                },
+                // Linear increase
+                6 => level += 0x20,
+                // Bent increase slows down past 3/4
+                _ => {
+                    level += if self.hidden_level < 0x600 {
+                        0x20
+                    } else {
+                        0x08
+                    }
+                },
+            }
+            gain.rate()
+        };
+
+        self.set_level(level, sustain_data, rate, counter);
+    }
+
+    fn set_level(&mut self, level: i32, sustain_data: u8, rate: u8, counter: u16) {
+        if self.mode == Mode::Decay && level >> 8 == i32::from(Adsr2(sustain_data).sustain_level())
+        {
+            self.mode = Mode::Sustain;
+        }
+        self.hidden_level = level;
+
+        if !(0..=0x7FF).contains(&level) && self.mode == Mode::Attack {
+            self.mode = Mode::Decay;
+        }
+        if rate_fires(counter, rate) {
+            self.level = level.clamp(0, 0x7FF) as u16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_for(envelope: &mut Envelope, samples: u16, adsr1: u8, adsr2: u8, gain: u8) {
+        for counter in (0..samples).rev() {
+            envelope.run(adsr1, adsr2, gain, counter);
+        }
+    }
+
+    #[test]
+    fn fastest_attack_reaches_the_top_in_two_samples() {
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+
+        run_for(&mut envelope, 1, 0x8F, 0xE0, 0);
+        assert_eq!(envelope.level, 0x400);
+        run_for(&mut envelope, 1, 0x8F, 0xE0, 0);
+        assert_eq!(envelope.level, 0x7FF);
+        assert!(envelope.mode == Mode::Decay);
+    }
+
+    #[test]
+    fn decay_stops_at_the_sustain_level() {
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+
+        // Fastest attack and decay, sustain level 4/8, sustain rate never
+        run_for(&mut envelope, 1000, 0xFF, 0x80, 0);
+        assert!(envelope.mode == Mode::Sustain);
+        // Sustain starts as soon as the next level would drop below $500
+        assert_eq!(envelope.level, 0x500);
+
+        let level = envelope.level;
+        run_for(&mut envelope, 1000, 0xFF, 0x80, 0);
+        assert_eq!(envelope.level, level);
+    }
+
+    #[test]
+    fn release_drops_8_every_sample() {
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+        run_for(&mut envelope, 2, 0x8F, 0xE0, 0);
+        envelope.release();
+
+        run_for(&mut envelope, 10, 0x8F, 0xE0, 0);
+        assert_eq!(envelope.level, 0x7FF - 80);
+    }
+
+    #[test]
+    fn direct_gain_sets_the_level() {
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+
+        run_for(&mut envelope, 1, 0x00, 0x00, 0x40);
+        assert_eq!(envelope.level, 0x400);
+    }
+
+    #[test]
+    fn gain_ramps_follow_the_rate() {
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+
+        // Linear increase at rate 31, every sample
+        run_for(&mut envelope, 4, 0x00, 0x00, 0xDF);
+        assert_eq!(envelope.level, 0x80);
+        // Linear decrease at rate 28, every 4 samples
+        run_for(&mut envelope, 8, 0x00, 0x00, 0x9C);
+        assert_eq!(envelope.level, 0x40);
+    }
+
+    #[test]
+    fn bent_increase_slows_down_past_0x600() {
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+
+        run_for(&mut envelope, 48, 0x00, 0x00, 0xFF);
+        assert_eq!(envelope.level, 0x600);
+        run_for(&mut envelope, 2, 0x00, 0x00, 0xFF);
+        assert_eq!(envelope.level, 0x610);
+    }
+}
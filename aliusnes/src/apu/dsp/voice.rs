@@ -0,0 +1,128 @@
+use crate::apu::dsp::brr::{self, Header};
+use crate::apu::dsp::envelope::Envelope;
+use crate::apu::dsp::gaussian;
+
+pub struct Voice {
+    brr_addr: u16,
+    // Offset of the next 2 data bytes inside the current block
+    brr_offset: u16,
+    buffer: [i16; 12],
+    // Oldest sample of the ring buffer
+    buffer_pos: usize,
+    // 4.12 fixed point position between the oldest samples
+    interp_pos: u16,
+    // Samples left before a keyed on voice starts playing
+    key_on_delay: u8,
+    pub envelope: Envelope,
+}
+
+impl Voice {
+    pub fn new() -> Self {
+        Self {
+            brr_addr: 0,
+            brr_offset: 1,
+            buffer: [0; 12],
+            buffer_pos: 0,
+            interp_pos: 0,
+            key_on_delay: 0,
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub fn key_on(&mut self, start_addr: u16) {
+        self.brr_addr = start_addr;
+        self.brr_offset = 1;
+        self.buffer = [0; 12];
+        self.buffer_pos = 0;
+        self.interp_pos = 0;
+        self.key_on_delay = 5;
+        self.envelope.key_on();
+    }
+
+    pub fn is_starting(&self) -> bool {
+        self.key_on_delay > 0
+    }
+
+    /// Current sample, or the noise generator output, scaled by the
+    /// envelope and 15 bits wide
+    pub fn output(&self, noise: Option<i16>) -> i32 {
+        let sample = match noise {
+            Some(noise) => i32::from(noise.wrapping_mul(2)),
+            None => gaussian::interpolate(&self.buffer, self.buffer_pos, self.interp_pos),
+        };
+        ((sample * i32::from(self.envelope.level)) >> 11) & !1
+    }
+
+    /// Moves `pitch` forward in the sample, decoding 4 more samples each time
+    /// the position crosses them. Returns true when a block with the end
+    /// flag was finished, then playback continues from `loop_addr`
+    pub fn advance(&mut self, aram: &[u8; 0x10000], pitch: u16, loop_addr: u16) -> bool {
+        if self.is_starting() {
+            // The delay fills the ring buffer with the first 12 samples
+            self.key_on_delay -= 1;
+            self.interp_pos = if self.key_on_delay & 3 != 0 {
+                0x4000
+            } else {
+                0
+            };
+        } else {
+            self.interp_pos = self.interp_pos.saturating_add(pitch).min(0x7FFF);
+        }
+        if self.interp_pos < 0x4000 {
+            return false;
+        }
+        self.interp_pos -= 0x4000;
+
+        let header = Header(aram[usize::from(self.brr_addr)]);
+        let data_addr = self.brr_addr.wrapping_add(self.brr_offset);
+        let data = [
+            aram[usize::from(data_addr)],
+            aram[usize::from(data_addr.wrapping_add(1))],
+        ];
+        brr::decode(header, data, &mut self.buffer, self.buffer_pos);
+        self.buffer_pos = (self.buffer_pos + 4) % 12;
+
+        self.brr_offset += 2;
+        if self.brr_offset < 9 {
+            return false;
+        }
+        self.brr_offset = 1;
+
+        if header.end() {
+            self.brr_addr = loop_addr;
+            if !header.looped() {
+                self.envelope.silence();
+            }
+        } else {
+            self.brr_addr = self.brr_addr.wrapping_add(9);
+        }
+        header.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pitch_0x1000_steps_one_sample_per_output() {
+        // A looping ramp from -8 to 7, 256 apart once decoded
+        let mut aram: Box<[u8; 0x10000]> = vec![0; 0x10000].into_boxed_slice().try_into().unwrap();
+        aram[0x200] = 0x83;
+        aram[0x201..0x209].copy_from_slice(&[0x89, 0xAB, 0xCD, 0xEF, 0x01, 0x23, 0x45, 0x67]);
+        let mut voice = Voice::new();
+        voice.key_on(0x200);
+        voice.envelope.level = 0x7FF;
+
+        let outputs: Vec<i32> = (0..18)
+            .map(|_| {
+                voice.advance(&aram, 0x1000, 0x200);
+                voice.output(None)
+            })
+            .collect();
+        // The interpolated ramp moves by one sample every output
+        for pair in outputs[4..].windows(2) {
+            assert!((254..=258).contains(&(pair[1] - pair[0])), "{outputs:?}");
+        }
+    }
+}
@@ -1 +1,161 @@
+use crate::apu::bus::ApuBus;
+pub use crate::apu::dsp::SAMPLE_RATE;
+use crate::apu::resampler::Resampler;
+use crate::apu::spc700::Spc700;
+use crate::bus::Access;
+use crate::cart::info::Model;
+
+mod bus;
+mod dsp;
+mod resampler;
 pub mod spc700;
+mod timer;
+
+const NTSC_MASTER_CLOCK_HZ: u64 = 21_477_272;
+const PAL_MASTER_CLOCK_HZ: u64 = 21_281_370;
+// The SPC700 has its own 24.576 MHz crystal, divided by 24
+const APU_CLOCK_HZ: u64 = 1_024_000;
+
+pub struct Apu {
+    spc700: Spc700<ApuBus>,
+    bus: ApuBus,
+    resampler: Option<Resampler>,
+    master_clock_hz: u64,
+}
+
+impl Apu {
+    pub fn new(model: Model) -> Self {
+        let master_clock_hz = match model {
+            Model::Ntsc => NTSC_MASTER_CLOCK_HZ,
+            Model::Pal => PAL_MASTER_CLOCK_HZ,
+        };
+        let mut apu = Self {
+            spc700: Spc700::new(),
+            bus: ApuBus::new(),
+            resampler: None,
+            master_clock_hz,
+        };
+        apu.spc700.reset(&mut apu.bus);
+        apu
+    }
+
+    /// Runs the SPC700 until it reaches the master clock `time`
+    pub fn catch_up(&mut self, time: u64) {
+        let target = time * APU_CLOCK_HZ / self.master_clock_hz;
+        while self.bus.cycles < target {
+            self.spc700.step(&mut self.bus);
+        }
+    }
+
+    /// Resamples the output to `rate`, or keeps the DSP rate with `None`
+    pub fn set_output_rate(&mut self, rate: Option<u32>) {
+        if self.resampler.as_ref().map(Resampler::output_rate) != rate {
+            self.resampler = rate.map(|rate| Resampler::new(SAMPLE_RATE, rate));
+        }
+    }
+
+    /// Moves the samples produced since the last call to `output`
+    pub fn drain_samples(&mut self, output: &mut Vec<[i16; 2]>) {
+        let samples = &mut self.bus.dsp.samples;
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(samples, output),
+            None => output.extend_from_slice(samples),
+        }
+        samples.clear();
+    }
+}
+
+impl Access for Apu {
+    fn read(&mut self, addr: u16, time: u64) -> Option<u8> {
+        self.catch_up(time);
+        Some(self.bus.apu_to_cpu[usize::from(addr & 3)])
+    }
+
+    /// The caller catches the APU up first, so the SPC700 sees the value
+    /// at the right time
+    fn write(&mut self, addr: u16, data: u8) {
+        self.bus.cpu_to_apu[usize::from(addr & 3)] = data;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Polls a port the way a CPU loop would, moving time forward
+    fn wait_for(apu: &mut Apu, time: &mut u64, port: u16, value: u8) {
+        for _ in 0..10_000 {
+            *time += 100;
+            if apu.read(0x2140 + port, *time) == Some(value) {
+                return;
+            }
+        }
+        panic!("APU port {port} never reached {value:#04x}");
+    }
+
+    fn write_port(apu: &mut Apu, time: u64, port: u16, data: u8) {
+        apu.catch_up(time);
+        apu.write(0x2140 + port, data);
+    }
+
+    #[test]
+    fn ipl_uploads_and_runs_a_program() {
+        // mov $F5, #$5A; bra -2
+        let program = [0x8F, 0x5A, 0xF5, 0x2F, 0xFE];
+        let mut apu = Apu::new(Model::Ntsc);
+        let mut time = 0;
+
+        wait_for(&mut apu, &mut time, 0, 0xAA);
+        wait_for(&mut apu, &mut time, 1, 0xBB);
+
+        write_port(&mut apu, time, 2, 0x00);
+        write_port(&mut apu, time, 3, 0x02);
+        write_port(&mut apu, time, 1, 0x01);
+        write_port(&mut apu, time, 0, 0xCC);
+        wait_for(&mut apu, &mut time, 0, 0xCC);
+
+        for (idx, &data) in program.iter().enumerate() {
+            write_port(&mut apu, time, 1, data);
+            write_port(&mut apu, time, 0, idx as u8);
+            wait_for(&mut apu, &mut time, 0, idx as u8);
+        }
+
+        // A zero in port 1 jumps to the address in ports 2 and 3
+        write_port(&mut apu, time, 2, 0x00);
+        write_port(&mut apu, time, 3, 0x02);
+        write_port(&mut apu, time, 1, 0x00);
+        write_port(&mut apu, time, 0, program.len() as u8 + 1);
+        wait_for(&mut apu, &mut time, 1, 0x5A);
+
+        assert_eq!(apu.bus.aram[0x200..0x205], program);
+    }
+
+    #[test]
+    fn drained_samples_follow_the_dsp_rate() {
+        let mut apu = Apu::new(Model::Ntsc);
+        let mut samples = Vec::new();
+
+        apu.catch_up(NTSC_MASTER_CLOCK_HZ / 10);
+        apu.drain_samples(&mut samples);
+        assert_eq!(samples.len(), 3200);
+
+        apu.set_output_rate(Some(48_000));
+        apu.catch_up(NTSC_MASTER_CLOCK_HZ / 5);
+        samples.clear();
+        apu.drain_samples(&mut samples);
+        assert_eq!(samples.len(), 4800);
+
+        apu.drain_samples(&mut samples);
+        assert_eq!(samples.len(), 4800);
+    }
+
+    #[test]
+    fn pal_consoles_use_their_master_clock() {
+        let mut apu = Apu::new(Model::Pal);
+        let mut samples = Vec::new();
+
+        apu.catch_up(PAL_MASTER_CLOCK_HZ / 10);
+        apu.drain_samples(&mut samples);
+        assert_eq!(samples.len(), 3200);
+    }
+}
@@ -0,0 +1,76 @@
+/// Converts stereo samples between rates by linear interpolation
+pub struct Resampler {
+    input_rate: u32,
+    output_rate: u32,
+    // Distance of the next output sample from `prev`, in 1/output_rate
+    // fractions of an input sample
+    pos: u32,
+    prev: [i16; 2],
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            input_rate,
+            output_rate,
+            pos: 0,
+            prev: [0; 2],
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    pub fn process(&mut self, input: &[[i16; 2]], output: &mut Vec<[i16; 2]>) {
+        for &next in input {
+            while self.pos < self.output_rate {
+                output.push(std::array::from_fn(|channel| {
+                    let prev = i64::from(self.prev[channel]);
+                    let delta = i64::from(next[channel]) - prev;
+                    (prev + delta * i64::from(self.pos) / i64::from(self.output_rate)) as i16
+                }));
+                self.pos += self.input_rate;
+            }
+            self.pos -= self.output_rate;
+            self.prev = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upsampling_keeps_the_rate_ratio() {
+        let mut resampler = Resampler::new(32_000, 48_000);
+        let mut output = Vec::new();
+
+        resampler.process(&[[100, -100]; 3200], &mut output);
+        assert_eq!(output.len(), 4800);
+        assert_eq!(output.last(), Some(&[100, -100]));
+    }
+
+    #[test]
+    fn downsampling_keeps_the_rate_ratio() {
+        let mut resampler = Resampler::new(32_000, 8_000);
+        let mut output = Vec::new();
+
+        resampler.process(&[[0, 0]; 320], &mut output);
+        assert_eq!(output.len(), 80);
+    }
+
+    #[test]
+    fn samples_in_between_are_interpolated() {
+        let mut resampler = Resampler::new(1, 2);
+        let mut output = Vec::new();
+
+        resampler.process(&[[0, 0], [100, -100]], &mut output);
+        resampler.process(&[[200, -200]], &mut output);
+        assert_eq!(
+            output,
+            [[0, 0], [0, 0], [0, 0], [50, -50], [100, -100], [150, -150]]
+        );
+    }
+}
@@ -38,6 +38,11 @@ impl Cpu {
         }
     }
 
+    pub fn reset<B: Bus>(&mut self, bus: &mut B) {
+        self.paused = false;
+        self.program_counter = self.read_16(bus, 0xFFFE);
+    }
+
     pub fn ya(&self) -> u16 {
         u16::from_le_bytes([self.accumulator, self.index_y])
     }
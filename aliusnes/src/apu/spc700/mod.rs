@@ -20,7 +20,17 @@ impl<B: Bus> Spc700<B> {
         }
     }
 
+    pub(crate) fn reset(&mut self, bus: &mut B) {
+        self.cpu.reset(bus);
+    }
+
     pub(crate) fn step(&mut self, bus: &mut B) {
+        // Sleep and stop halt the core, only a reset wakes it up
+        if self.cpu.paused {
+            bus.add_io_cycles(1);
+            return;
+        }
+
         let op = self.cpu.get_imm::<B>(bus);
         let opcode = &self.instruction_set[op as usize];
 
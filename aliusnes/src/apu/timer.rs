@@ -0,0 +1,107 @@
+pub struct Timer {
+    // SPC700 cycles per stage 2 tick, 128 for 8 kHz and 16 for 64 kHz
+    period: u16,
+    enabled: bool,
+    target: u8,
+    stage1: u16,
+    stage2: u8,
+    counter: u8,
+}
+
+impl Timer {
+    pub fn new(period: u16) -> Self {
+        Self {
+            period,
+            enabled: false,
+            target: 0,
+            stage1: 0,
+            stage2: 0,
+            counter: 0,
+        }
+    }
+
+    /// Enabling a stopped timer restarts its count
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled && !self.enabled {
+            self.stage2 = 0;
+            self.counter = 0;
+        }
+        self.enabled = enabled;
+    }
+
+    /// A target of 0 counts 256 ticks
+    pub fn set_target(&mut self, target: u8) {
+        self.target = target;
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut cycles = cycles + u64::from(self.stage1);
+        while cycles >= u64::from(self.period) {
+            cycles -= u64::from(self.period);
+            self.stage2 = self.stage2.wrapping_add(1);
+            if self.stage2 == self.target {
+                self.stage2 = 0;
+                self.counter = (self.counter + 1) & 0xF;
+            }
+        }
+        self.stage1 = cycles as u16;
+    }
+
+    /// The 4-bit up-counter clears when read
+    pub fn read_counter(&mut self) -> u8 {
+        std::mem::take(&mut self.counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_ticks_on_target_and_clears_on_read() {
+        let mut timer = Timer::new(128);
+        timer.set_target(2);
+        timer.set_enabled(true);
+
+        timer.tick(128 * 2 * 3 + 127);
+        assert_eq!(timer.read_counter(), 3);
+        assert_eq!(timer.read_counter(), 0);
+
+        timer.tick(128 + 1);
+        assert_eq!(timer.read_counter(), 1);
+    }
+
+    #[test]
+    fn counter_wraps_at_four_bits() {
+        let mut timer = Timer::new(16);
+        timer.set_target(1);
+        timer.set_enabled(true);
+
+        timer.tick(16 * 17);
+        assert_eq!(timer.read_counter(), 1);
+    }
+
+    #[test]
+    fn zero_target_counts_256_ticks() {
+        let mut timer = Timer::new(16);
+        timer.set_enabled(true);
+
+        timer.tick(16 * 255);
+        assert_eq!(timer.read_counter(), 0);
+        timer.tick(16);
+        assert_eq!(timer.read_counter(), 1);
+    }
+
+    #[test]
+    fn disabled_timer_does_not_count() {
+        let mut timer = Timer::new(16);
+        timer.set_target(1);
+
+        timer.tick(16 * 4);
+        assert_eq!(timer.read_counter(), 0);
+    }
+}
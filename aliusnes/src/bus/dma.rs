@@ -12,6 +12,21 @@ bitfield! {
     }
 }
 
+impl Parameters {
+    /// B-bus address offsets written by one transfer unit
+    fn transfer_unit(self) -> &'static [u8] {
+        match self.transfer_pattern() {
+            0 => &[0],
+            1 => &[0, 1],
+            2 | 6 => &[0, 0],
+            3 | 7 => &[0, 0, 1, 1],
+            4 => &[0, 1, 2, 3],
+            5 => &[0, 1, 0, 1],
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Channel {
     parameters: Parameters,
@@ -23,6 +38,8 @@ pub struct Channel {
     h_curr_addr: u16,
     h_reload_or_scanline_count: u8,
     unused: u8,
+    h_do_transfer: bool,
+    h_terminated: bool,
 }
 
 pub struct Dma {
@@ -44,68 +61,200 @@ impl Dma {
                 h_curr_addr: 0xFF,
                 h_reload_or_scanline_count: 0xFF,
                 unused: 0xFF,
+                h_do_transfer: false,
+                h_terminated: true,
             }; 8],
             enable_channels: 0,
             h_enable_channels: 0,
         }
     }
 
+    /// Runs the enabled channels one byte at a time, handling the due
+    /// events between bytes so PPU timing and HDMA keep running.
+    /// HDMA on the same channel stops the transfer
     pub fn do_dma(bus: &mut SystemBus) {
+        let start = bus.scheduler.cycles;
+        // DMA starts aligned to a multiple of 8 master cycles
+        bus.add_cycles(((8 - bus.scheduler.cycles % 8) % 8) as usize);
+        bus.add_cycles(8);
+        bus.process_events();
+
         for index in 0..8 {
             if bus.dma.enable_channels & (1 << index) == 0 {
                 continue;
             }
             let channel = bus.dma.channels[index];
-            let count = if channel.byte_count_or_h_indirect_addr == 0 {
-                0x10000
-            } else {
-                channel.byte_count_or_h_indirect_addr as usize
-            };
-
-            let pattern: Vec<u8> = match channel.parameters.transfer_pattern() {
-                0 => vec![0],
-                1 => vec![0, 1],
-                2 | 6 => vec![0, 0],
-                3 | 7 => vec![0, 0, 1, 1],
-                4 => vec![0, 1, 2, 3],
-                5 => vec![0, 1, 0, 1],
-                _ => unreachable!(),
-            };
-
-            for i in 0..count {
+            let pattern = channel.parameters.transfer_unit();
+            bus.add_cycles(8);
+
+            let mut unit_index = 0;
+            loop {
                 let bank = bus.dma.channels[index].a_bank_or_h_table_bank;
                 let offset = bus.dma.channels[index].a_addr_or_h_table_addr;
                 let a_addr = Address::new(offset, bank);
-                let byte = channel.b_addr.wrapping_add(pattern[i % pattern.len()]);
-
-                // WRAM to WRAM is invalid
-                if byte == 0x80
-                    && ((u32::from(a_addr) & 0xFE_0000) == 0x7E_0000
-                        || (u32::from(a_addr) & 0x40_E000) == 0)
-                {
-                    continue;
-                }
-
-                let b_addr = 0x2100 | u16::from(byte);
-
-                if channel.parameters.direction() {
-                    let data = bus.read_b(b_addr);
-                    bus.write::<true>(a_addr, data);
-                } else {
-                    let data = bus.read::<true>(a_addr);
-                    bus.write_b(b_addr, data);
-                }
+                let byte = channel
+                    .b_addr
+                    .wrapping_add(pattern[unit_index % pattern.len()]);
+                Self::transfer(bus, channel.parameters, a_addr, byte);
+                bus.add_cycles(8);
+                unit_index += 1;
 
                 match channel.parameters.address_adjust_mode() {
                     0 => bus.dma.channels[index].a_addr_or_h_table_addr = offset.wrapping_add(1),
                     2 => bus.dma.channels[index].a_addr_or_h_table_addr = offset.wrapping_sub(1),
                     _ => (),
                 }
+
+                // A byte count of 0 transfers 65536 bytes
+                let count = &mut bus.dma.channels[index].byte_count_or_h_indirect_addr;
+                *count = count.wrapping_sub(1);
+                if *count == 0 {
+                    break;
+                }
+
+                bus.process_events();
+                if bus.dma.enable_channels & (1 << index) == 0 {
+                    break;
+                }
             }
-            bus.dma.channels[index].byte_count_or_h_indirect_addr = 0;
-            bus.add_cycles(8 + (8 * count) + 2);
+            bus.dma.enable_channels &= !(1 << index);
+            bus.process_events();
         }
         bus.dma.enable_channels = 0;
+
+        // The CPU resumes once its clock is aligned again
+        let length = bus.cpu_cycle_length;
+        bus.add_cycles(((length - (bus.scheduler.cycles - start) % length) % length) as usize);
+        bus.process_events();
+    }
+
+    fn transfer(bus: &mut SystemBus, parameters: Parameters, a_addr: Address, byte: u8) {
+        // WRAM to WRAM is invalid
+        if byte == 0x80
+            && ((u32::from(a_addr) & 0xFE_0000) == 0x7E_0000
+                || (u32::from(a_addr) & 0x40_E000) == 0)
+        {
+            return;
+        }
+
+        let b_addr = 0x2100 | u16::from(byte);
+
+        if parameters.direction() {
+            let data = bus.read_b(b_addr);
+            bus.write::<true>(a_addr, data);
+        } else {
+            let data = bus.read::<true>(a_addr);
+            bus.write_b(b_addr, data);
+        }
+    }
+
+    /// Restarts every enabled HDMA channel from the beginning of its table
+    /// at the start of the frame
+    pub fn init_hdma(bus: &mut SystemBus) {
+        for channel in &mut bus.dma.channels {
+            channel.h_do_transfer = true;
+            channel.h_terminated = false;
+        }
+        let h_enable_channels = bus.dma.h_enable_channels;
+        if h_enable_channels == 0 {
+            return;
+        }
+
+        bus.add_cycles(18);
+        for index in 0..8 {
+            if h_enable_channels & (1 << index) == 0 {
+                continue;
+            }
+            // HDMA takes over a general DMA set on the same channel
+            bus.dma.enable_channels &= !(1 << index);
+            bus.dma.channels[index].h_curr_addr = bus.dma.channels[index].a_addr_or_h_table_addr;
+            Self::reload_hdma_channel(bus, index);
+            bus.add_cycles(8);
+        }
+    }
+
+    /// Runs one line of every active HDMA channel during H-blank
+    pub fn do_hdma(bus: &mut SystemBus) {
+        let active_channels: Vec<usize> = (0..8)
+            .filter(|&index| {
+                bus.dma.h_enable_channels & (1 << index) != 0
+                    && !bus.dma.channels[index].h_terminated
+            })
+            .collect();
+        if active_channels.is_empty() {
+            return;
+        }
+
+        bus.add_cycles(18);
+        for index in active_channels {
+            bus.dma.enable_channels &= !(1 << index);
+            let channel = bus.dma.channels[index];
+
+            if channel.h_do_transfer {
+                for offset in channel.parameters.transfer_unit() {
+                    let a_addr = Self::hdma_data_addr(&mut bus.dma.channels[index]);
+                    Self::transfer(
+                        bus,
+                        channel.parameters,
+                        a_addr,
+                        channel.b_addr.wrapping_add(*offset),
+                    );
+                    bus.add_cycles(8);
+                }
+            }
+
+            // Bit 7 of the line counter selects repeat mode, where the
+            // transfer happens on every line instead of only the first one
+            let line_counter = channel.h_reload_or_scanline_count.wrapping_sub(1);
+            bus.dma.channels[index].h_reload_or_scanline_count = line_counter;
+            bus.dma.channels[index].h_do_transfer = line_counter & 0x80 != 0;
+            if line_counter & 0x7F == 0 {
+                Self::reload_hdma_channel(bus, index);
+            }
+            bus.add_cycles(8);
+        }
+    }
+
+    /// Direct tables hold the data after the line counter, indirect
+    /// tables hold its address in the channel indirect bank
+    fn hdma_data_addr(channel: &mut Channel) -> Address {
+        if channel.parameters.h_indirect() {
+            let addr = Address::new(
+                channel.byte_count_or_h_indirect_addr,
+                channel.h_indirect_bank,
+            );
+            channel.byte_count_or_h_indirect_addr =
+                channel.byte_count_or_h_indirect_addr.wrapping_add(1);
+            addr
+        } else {
+            let addr = Address::new(channel.h_curr_addr, channel.a_bank_or_h_table_bank);
+            channel.h_curr_addr = channel.h_curr_addr.wrapping_add(1);
+            addr
+        }
+    }
+
+    fn read_hdma_table(bus: &mut SystemBus, index: usize) -> u8 {
+        let channel = &mut bus.dma.channels[index];
+        let addr = Address::new(channel.h_curr_addr, channel.a_bank_or_h_table_bank);
+        channel.h_curr_addr = channel.h_curr_addr.wrapping_add(1);
+        bus.read::<true>(addr)
+    }
+
+    /// Loads the next line counter of the table, followed by the data
+    /// address in indirect mode. A zero line counter ends the channel
+    /// until the next frame
+    fn reload_hdma_channel(bus: &mut SystemBus, index: usize) {
+        let line_counter = Self::read_hdma_table(bus, index);
+        bus.dma.channels[index].h_reload_or_scanline_count = line_counter;
+
+        if bus.dma.channels[index].parameters.h_indirect() {
+            let low = Self::read_hdma_table(bus, index);
+            let high = Self::read_hdma_table(bus, index);
+            bus.dma.channels[index].byte_count_or_h_indirect_addr = u16::from_le_bytes([low, high]);
+            bus.add_cycles(16);
+        }
+        bus.dma.channels[index].h_do_transfer = true;
+        bus.dma.channels[index].h_terminated = line_counter == 0;
     }
 }
 
@@ -154,3 +303,133 @@ impl Access for Dma {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::load_cart;
+    use crate::scheduler::{DmaEvent, Event};
+
+    /// A LoROM cart whose first bytes, mapped at $00:8000, hold `table`.
+    /// HDMA writes to the WRAM port starting at $7E:1000
+    fn bus_with_hdma_table(parameters: u8, table: &[u8]) -> SystemBus {
+        let mut rom = vec![0; 0x8000];
+        rom[..table.len()].copy_from_slice(table);
+        let mut bus = SystemBus::new(load_cart(&rom, Vec::new()));
+
+        for (addr, data) in [(0x2181, 0x00), (0x2182, 0x10), (0x2183, 0x00)] {
+            bus.write_b(addr, data);
+        }
+        for (offset, data) in [(0x0, parameters), (0x1, 0x80), (0x2, 0x00), (0x3, 0x80)] {
+            bus.write::<false>(Address::new(0x4300 + offset, 0x00), data);
+        }
+        bus.write::<false>(Address::new(0x420C, 0x00), 0x01);
+        bus
+    }
+
+    fn wram(bus: &mut SystemBus, len: u16) -> Vec<u8> {
+        (0..len)
+            .map(|offset| bus.read::<false>(Address::new(0x1000 + offset, 0x7E)))
+            .collect()
+    }
+
+    #[test]
+    fn direct_table_with_repeat_and_termination() {
+        let mut bus = bus_with_hdma_table(0x00, &[0x02, 0xAA, 0x81, 0xBB, 0x00, 0xCC]);
+
+        Dma::init_hdma(&mut bus);
+        Dma::do_hdma(&mut bus);
+        assert_eq!(wram(&mut bus, 2), [0xAA, 0x00]);
+
+        // The second line of a non repeated entry does not transfer
+        Dma::do_hdma(&mut bus);
+        assert_eq!(wram(&mut bus, 2), [0xAA, 0x00]);
+
+        Dma::do_hdma(&mut bus);
+        Dma::do_hdma(&mut bus);
+        assert_eq!(wram(&mut bus, 3), [0xAA, 0xBB, 0x00]);
+    }
+
+    #[test]
+    fn indirect_table_reads_data_from_its_address() {
+        let mut table = vec![0; 0x12];
+        table[..4].copy_from_slice(&[0x82, 0x10, 0x80, 0x00]);
+        table[0x10..].copy_from_slice(&[0x11, 0x22]);
+        let mut bus = bus_with_hdma_table(0x40, &table);
+        bus.write::<false>(Address::new(0x4307, 0x00), 0x00);
+
+        Dma::init_hdma(&mut bus);
+        Dma::do_hdma(&mut bus);
+        Dma::do_hdma(&mut bus);
+        Dma::do_hdma(&mut bus);
+        assert_eq!(wram(&mut bus, 3), [0x11, 0x22, 0x00]);
+        assert!(bus.dma.channels[0].h_terminated);
+    }
+
+    fn start_dma(bus: &mut SystemBus, count: u8) {
+        bus.write::<false>(Address::new(0x4305, 0x00), count);
+        bus.write::<false>(Address::new(0x4306, 0x00), 0x00);
+        bus.write::<false>(Address::new(0x420B, 0x00), 0x01);
+    }
+
+    #[test]
+    fn dma_takes_8_cycles_per_byte_after_overheads() {
+        let mut bus = bus_with_hdma_table(0x00, &[1, 2, 3, 4]);
+        bus.write::<false>(Address::new(0x420C, 0x00), 0x00);
+        start_dma(&mut bus, 4);
+        let start = bus.scheduler.cycles;
+
+        Dma::do_dma(&mut bus);
+
+        // Start and channel overheads, then 4 bytes from slow ROM
+        assert_eq!(bus.scheduler.cycles - start, 8 + 8 + 4 * 8);
+        assert_eq!(wram(&mut bus, 5), [1, 2, 3, 4, 0]);
+        assert_eq!(bus.dma.enable_channels, 0);
+    }
+
+    #[test]
+    fn dma_from_the_cpu_registers_area_takes_8_cycles_per_byte() {
+        let mut bus = bus_with_hdma_table(0x00, &[]);
+        bus.write::<false>(Address::new(0x420C, 0x00), 0x00);
+        bus.write::<false>(Address::new(0x4303, 0x00), 0x40);
+        start_dma(&mut bus, 4);
+        let start = bus.scheduler.cycles;
+
+        Dma::do_dma(&mut bus);
+
+        assert_eq!(bus.scheduler.cycles - start, 8 + 8 + 4 * 8);
+    }
+
+    #[test]
+    fn dma_realigns_the_cpu_clock_at_the_end() {
+        let mut bus = bus_with_hdma_table(0x00, &[1, 2, 3, 4]);
+        bus.write::<false>(Address::new(0x420C, 0x00), 0x00);
+        bus.write::<false>(Address::new(0x4305, 0x00), 4);
+        bus.write::<false>(Address::new(0x4306, 0x00), 0x00);
+        let start = bus.scheduler.cycles;
+        // The 6 cycles write leaves the CPU off the 8 cycles grid
+        bus.write_and_tick(Address::new(0x420B, 0x00), 0x01);
+
+        Dma::do_dma(&mut bus);
+
+        // 2 cycles to align, the overheads and 4 bytes, then 4 cycles to
+        // end on a 6 cycles boundary of the CPU clock
+        assert_eq!(bus.scheduler.cycles - start, 6 + 2 + 8 + 8 + 4 * 8 + 4);
+    }
+
+    #[test]
+    fn hdma_on_the_same_channel_stops_the_dma() {
+        let mut bus = bus_with_hdma_table(0x00, &[0x81, 0xAA, 0x00]);
+        Dma::init_hdma(&mut bus);
+        start_dma(&mut bus, 4);
+        let time = bus.scheduler.cycles + 20;
+        bus.scheduler
+            .add_event(Event::Dma(DmaEvent::HdmaTransfer), time);
+
+        Dma::do_dma(&mut bus);
+
+        assert_eq!(bus.dma.channels[0].byte_count_or_h_indirect_addr, 3);
+        assert!(bus.dma.channels[0].h_terminated);
+    }
+}
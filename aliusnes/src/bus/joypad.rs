@@ -0,0 +1,166 @@
+use crate::bus::Access;
+
+bitfield! {
+    /// Buttons of a standard controller, the serial port shifts them out
+    /// starting from B
+    #[derive(Clone, Copy, Default)]
+    pub struct Buttons(pub u16) {
+        pub r: bool @ 4,
+        pub l: bool @ 5,
+        pub x: bool @ 6,
+        pub a: bool @ 7,
+        pub right: bool @ 8,
+        pub left: bool @ 9,
+        pub down: bool @ 10,
+        pub up: bool @ 11,
+        pub start: bool @ 12,
+        pub select: bool @ 13,
+        pub y: bool @ 14,
+        pub b: bool @ 15,
+    }
+}
+
+// Auto-read shifts 16 bits from each port over about 3 scanlines
+pub const AUTO_READ_CYCLES: u64 = 4224;
+
+pub struct Joypad {
+    buttons: [Buttons; 2],
+    shift_registers: [u16; 2],
+    latch: bool,
+    auto_read_data: [u16; 2],
+    pub auto_read_busy: bool,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            buttons: [Buttons::default(); 2],
+            shift_registers: [0; 2],
+            latch: false,
+            auto_read_data: [0; 2],
+            auto_read_busy: false,
+        }
+    }
+
+    /// Only ports 0 and 1 exist, other ports are ignored
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        if let Some(held) = self.buttons.get_mut(port) {
+            *held = buttons;
+        }
+    }
+
+    fn latch_buttons(&mut self) {
+        for (register, buttons) in self.shift_registers.iter_mut().zip(self.buttons) {
+            *register = buttons.0;
+        }
+    }
+
+    /// Once the 16 buttons are out the port keeps returning 1
+    fn read_serial(&mut self, port: usize) -> u8 {
+        if self.latch {
+            self.latch_buttons();
+        }
+        let bit = (self.shift_registers[port] >> 15) as u8;
+        self.shift_registers[port] = (self.shift_registers[port] << 1) | 1;
+        bit
+    }
+
+    pub fn start_auto_read(&mut self) {
+        self.latch_buttons();
+        for port in 0..2 {
+            self.auto_read_data[port] = 0;
+            for _ in 0..16 {
+                self.auto_read_data[port] =
+                    (self.auto_read_data[port] << 1) | u16::from(self.read_serial(port));
+            }
+        }
+        self.auto_read_busy = true;
+    }
+}
+
+impl Access for Joypad {
+    fn read(&mut self, addr: u16, _: u64) -> Option<u8> {
+        match addr {
+            0x4016 => Some(self.read_serial(0)),
+            // Bits 2-4 of the second port are always set
+            0x4017 => Some(self.read_serial(1) | 0x1C),
+            0x4218..=0x421F => {
+                let port = usize::from((addr >> 1) & 1);
+                // Only a controller on each port, the second data line is unused
+                let data = if addr >= 0x421C {
+                    0
+                } else {
+                    self.auto_read_data[port]
+                };
+                Some((data >> ((addr & 1) << 3)) as u8)
+            },
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4016 => {
+                self.latch = data & 1 != 0;
+                if self.latch {
+                    self.latch_buttons();
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joypad_with_buttons() -> Joypad {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(0, Buttons(0).with_b(true).with_start(true).with_r(true));
+        joypad.set_buttons(1, Buttons(0).with_a(true));
+        joypad
+    }
+
+    #[test]
+    fn serial_port_shifts_buttons_from_b_then_ones() {
+        let mut joypad = joypad_with_buttons();
+        joypad.write(0x4016, 1);
+        joypad.write(0x4016, 0);
+
+        let bits: Vec<u8> = (0..17).map(|_| joypad.read(0x4016, 0).unwrap()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn buttons_on_missing_ports_are_ignored() {
+        let mut joypad = joypad_with_buttons();
+        joypad.set_buttons(2, Buttons(0xFFFF));
+        assert_eq!(joypad.buttons.map(|buttons| buttons.0), [0x9010, 0x0080]);
+    }
+
+    #[test]
+    fn latch_high_keeps_returning_the_first_button() {
+        let mut joypad = joypad_with_buttons();
+        joypad.write(0x4016, 1);
+
+        assert_eq!(joypad.read(0x4016, 0), Some(1));
+        assert_eq!(joypad.read(0x4016, 0), Some(1));
+        assert_eq!(joypad.read(0x4017, 0), Some(0x1C));
+    }
+
+    #[test]
+    fn auto_read_fills_the_joy_registers() {
+        let mut joypad = joypad_with_buttons();
+        joypad.start_auto_read();
+
+        assert!(joypad.auto_read_busy);
+        assert_eq!(joypad.read(0x4218, 0), Some(0x10));
+        assert_eq!(joypad.read(0x4219, 0), Some(0x90));
+        assert_eq!(joypad.read(0x421A, 0), Some(0x80));
+        assert_eq!(joypad.read(0x421B, 0), Some(0x00));
+        assert_eq!(joypad.read(0x421C, 0), Some(0x00));
+        // The serial ports were emptied by the auto-read
+        assert_eq!(joypad.read(0x4016, 0), Some(1));
+    }
+}
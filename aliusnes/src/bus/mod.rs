@@ -1,4 +1,5 @@
 pub(super) mod dma;
+pub(super) mod joypad;
 mod math;
 pub mod system_bus;
 mod wram;
@@ -1,22 +1,27 @@
+use crate::apu::Apu;
 use crate::bus::dma::Dma;
+use crate::bus::joypad::{AUTO_READ_CYCLES, Joypad};
 use crate::bus::math::Math;
 use crate::bus::wram::Wram;
 use crate::bus::{Access, Address, Bus};
 use crate::cart::Cart;
 use crate::ppu::Ppu;
-use crate::scheduler::Scheduler;
+use crate::scheduler::{DmaEvent, Event, JoypadEvent, Scheduler};
 use crate::utils::int_traits::ManipulateU16;
 
 pub struct SystemBus {
     mdr: u8,
     fast_rom_enabled: bool,
+    // Length of the last CPU cycle, DMA realigns the CPU clock to it
+    pub cpu_cycle_length: u64,
+    pub apu: Apu,
     cart: Cart,
     pub dma: Dma,
+    pub joypad: Joypad,
     math: Math,
     pub ppu: Ppu,
     pub scheduler: Scheduler,
     wram: Wram,
-    dummy_apu: [u8; 4],
 }
 
 impl SystemBus {
@@ -24,13 +29,15 @@ impl SystemBus {
         Self {
             mdr: 0,
             fast_rom_enabled: false,
+            cpu_cycle_length: 8,
+            apu: Apu::new(cart.model),
             ppu: Ppu::new(cart.model),
             scheduler: Scheduler::new(),
             cart,
             dma: Dma::new(),
+            joypad: Joypad::new(),
             math: Math::new(),
             wram: Wram::new(),
-            dummy_apu: [0xAA, 0, 0, 0],
         }
     }
 
@@ -38,21 +45,31 @@ impl SystemBus {
         self.scheduler.tick(cycles as u64);
     }
 
+    /// Handles every event that is due at the current time
+    pub fn process_events(&mut self) {
+        while let Some((event, time)) = self.scheduler.pop_event() {
+            match event {
+                Event::Ppu(ppu_event) => {
+                    self.ppu.handle_event(&mut self.scheduler, ppu_event, time)
+                },
+                Event::Dma(DmaEvent::HdmaInit) => Dma::init_hdma(self),
+                Event::Dma(DmaEvent::HdmaTransfer) => Dma::do_hdma(self),
+                Event::Joypad(JoypadEvent::AutoReadStart) => {
+                    self.joypad.start_auto_read();
+                    self.scheduler.add_event(
+                        Event::Joypad(JoypadEvent::AutoReadEnd),
+                        time + AUTO_READ_CYCLES,
+                    );
+                },
+                Event::Joypad(JoypadEvent::AutoReadEnd) => self.joypad.auto_read_busy = false,
+            }
+        }
+    }
+
     pub fn read_b(&mut self, addr: u16) -> u8 {
         if let Some(val) = match addr.low_byte() {
-            0x34..=0x3F => self.ppu.read(addr, self.scheduler.cycles),
-            0x40..=0x43 => {
-                let ch = ((addr - 0x2140) % 4) as usize;
-
-                let value = self.dummy_apu[ch];
-                self.dummy_apu[ch] = match ch {
-                    0 => 0xAA,
-                    1 => 0xBB,
-                    _ => 0,
-                };
-
-                Some(value)
-            },
+            0x00..=0x3F => self.ppu.read(addr, self.scheduler.cycles),
+            0x40..=0x7F => self.apu.read(addr, self.scheduler.cycles),
             0x80 => self.wram.read(addr, 0),
             _ => None,
         } {
@@ -75,27 +92,23 @@ impl SystemBus {
             0x00..=0x3F | 0x80..=0xBF => match page.high_byte() {
                 0x00..=0x1F => Some(self.wram.ram[page as usize & 0x1FFF]),
                 0x21 => Some(self.read_b(page)),
-                0x40..=0x43 => {
-                    match page {
-                        0x4210 => Some(self.ppu.read_nmi_flag() | (self.mdr & 0x70)),
-                        0x4211 => Some(self.ppu.read_irq_flag() | (self.mdr & 0x7F)),
-                        0x4212 => {
-                            let joypad_autoread_status = false; // todo
-                            Some(
-                                self.ppu.read_hv_status()
-                                    | u8::from(joypad_autoread_status)
-                                    | (self.mdr & 0x3E),
-                            )
-                        },
-                        0x4214..=0x4217 => self.math.read(page, 0),
-                        // TODO joypad registers
-                        0x4218..=0x421F => Some(0),
-                        0x4300..=0x437F => self.dma.read(page, 0),
-                        _ => {
-                            println!("Tried to read at {page:#0x}");
-                            None
-                        },
-                    }
+                0x40..=0x43 => match page {
+                    0x4210 => Some(self.ppu.read_nmi_flag() | (self.mdr & 0x70)),
+                    0x4211 => Some(self.ppu.read_irq_flag() | (self.mdr & 0x7F)),
+                    0x4016 => self.joypad.read(page, 0).map(|val| val | (self.mdr & 0xFC)),
+                    0x4017 => self.joypad.read(page, 0).map(|val| val | (self.mdr & 0xE0)),
+                    0x4212 => Some(
+                        self.ppu.read_hv_status()
+                            | u8::from(self.joypad.auto_read_busy)
+                            | (self.mdr & 0x3E),
+                    ),
+                    0x4214..=0x4217 => self.math.read(page, 0),
+                    0x4218..=0x421F => self.joypad.read(page, 0),
+                    0x4300..=0x437F => self.dma.read(page, 0),
+                    _ => {
+                        println!("Tried to read at {page:#0x}");
+                        None
+                    },
                 },
                 _ => None,
             },
@@ -115,10 +128,13 @@ impl SystemBus {
 
     pub fn write_b(&mut self, addr: u16, data: u8) {
         match addr.low_byte() {
-            0x00..=0x33 => self.ppu.write(addr, data),
-            0x40..=0x43 => {
-                let ch = ((addr - 0x2140) % 4) as usize;
-                self.dummy_apu[ch] = data;
+            0x00..=0x33 => {
+                self.ppu.catch_up(self.scheduler.cycles);
+                self.ppu.write(addr, data);
+            },
+            0x40..=0x7F => {
+                self.apu.catch_up(self.scheduler.cycles);
+                self.apu.write(addr, data);
             },
             0x80..=0x83 => self.wram.write(addr, data),
             _ => println!("Tried to write at {addr:#0x} val: {data:#04x}"),
@@ -135,6 +151,7 @@ impl SystemBus {
                 0x21 if !DMA => self.write_b(page, data),
                 0x40..=0x43 if !DMA => {
                     return match page {
+                        0x4016 => self.joypad.write(page, data),
                         0x4200 => self.ppu.write_nmitien(data),
                         0x4202..=0x4206 => self.math.write(page, data),
                         0x4207 => self.ppu.set_h_timer_low(data),
@@ -203,18 +220,19 @@ impl Bus for SystemBus {
     }
 
     fn read_and_tick(&mut self, addr: Address) -> u8 {
-        self.scheduler
-            .tick(u64::from(self.memory_access_cycles(addr)));
+        self.cpu_cycle_length = u64::from(self.memory_access_cycles(addr));
+        self.scheduler.tick(self.cpu_cycle_length);
         self.read::<false>(addr)
     }
 
     fn write_and_tick(&mut self, addr: Address, data: u8) {
-        self.scheduler
-            .tick(u64::from(self.memory_access_cycles(addr)));
+        self.cpu_cycle_length = u64::from(self.memory_access_cycles(addr));
+        self.scheduler.tick(self.cpu_cycle_length);
         self.write::<false>(addr, data);
     }
 
     fn add_io_cycles(&mut self, cycles: usize) {
+        self.cpu_cycle_length = 6;
         self.scheduler.tick((cycles * 6) as u64);
     }
 
@@ -1,6 +1,9 @@
+pub use crate::apu::SAMPLE_RATE;
 use crate::bus::dma::Dma;
+pub use crate::bus::joypad::Buttons;
 use crate::bus::system_bus::SystemBus;
 use crate::cart::Cart;
+pub use crate::ppu::{FB_HEIGHT, FB_WIDTH};
 use crate::scheduler::{Event, PpuEvent};
 use crate::w65c816::W65C816;
 
@@ -40,12 +43,7 @@ impl Emu {
             Dma::do_dma(bus);
         }
         w65c816.step(bus);
-
-        while let Some((event, time)) = bus.scheduler.pop_event() {
-            match event {
-                Event::Ppu(ppu_event) => bus.ppu.handle_event(&mut bus.scheduler, ppu_event, time),
-            }
-        }
+        bus.process_events();
     }
 
     pub fn run_cpu_until_next_event(&mut self) {
@@ -66,16 +64,9 @@ impl Emu {
     pub fn run_frame(&mut self) {
         while !self.frame_ready() {
             self.run_cpu_until_next_event();
-            while let Some((event, time)) = self.bus.scheduler.pop_event() {
-                match event {
-                    Event::Ppu(ppu_event) => {
-                        self.bus
-                            .ppu
-                            .handle_event(&mut self.bus.scheduler, ppu_event, time);
-                    },
-                }
-            }
+            self.bus.process_events();
         }
+        self.bus.apu.catch_up(self.bus.scheduler.cycles);
         self.bus.ppu.frame_ready = false;
     }
 
@@ -85,6 +76,38 @@ impl Emu {
         }
     }
 
+    /// Sets the buttons held on the controller plugged in `port`, 0 or 1,
+    /// any other port is ignored
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.bus.joypad.set_buttons(port, buttons);
+    }
+
+    /// Real hardware drops VRAM, OAM and CGRAM writes made during active
+    /// display, disabling the restrictions helps debugging code that does so
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.bus.ppu.access_restrictions = enabled;
+    }
+
+    /// Draws each line in pieces as PPU registers are written, showing
+    /// mid-line raster effects at the cost of speed
+    pub fn set_dot_renderer(&mut self, enabled: bool) {
+        self.bus.ppu.dot_renderer = enabled;
+    }
+
+    /// Resamples the audio handed out by `drain_audio` to `rate` Hz, `None`
+    /// keeps the native `SAMPLE_RATE`
+    pub fn set_audio_rate(&mut self, rate: Option<u32>) {
+        self.bus.apu.set_output_rate(rate);
+    }
+
+    /// Appends to `samples` the stereo samples produced since the last call,
+    /// as left and right pairs. At most a second of audio is kept between
+    /// calls, past that the oldest half second is dropped
+    pub fn drain_audio(&mut self, samples: &mut Vec<[i16; 2]>) {
+        self.bus.apu.catch_up(self.bus.scheduler.cycles);
+        self.bus.apu.drain_samples(samples);
+    }
+
     pub fn frame_ready(&self) -> bool {
         self.bus.ppu.frame_ready
     }
@@ -93,12 +116,15 @@ impl Emu {
         self.bus.ppu.screen_width
     }
 
+    /// Visible lines of the last frame, 224 or 239 in overscan mode,
+    /// doubled for interlaced frames
     pub fn frame_height(&self) -> usize {
         self.bus.ppu.screen_height
     }
 
+    /// The last complete frame, `frame_width` pixels per row
     pub fn frame(&self) -> &[[u8; 3]] {
-        self.bus.ppu.frame_buffer.as_slice()
+        &self.bus.ppu.frame_buffer[..self.frame_width() * self.frame_height()]
     }
 }
 
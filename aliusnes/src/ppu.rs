@@ -1,12 +1,14 @@
 use crate::bus::Access;
 use crate::cart::info::Model;
-use crate::ppu::background::{Background, BgMode, Mosaic};
+use crate::ppu::background::{Background, BgMode};
 use crate::ppu::color::{Cgadsub, Cgwsel, Color, ColorData};
 use crate::ppu::counters::Counters;
-use crate::ppu::mode7::Mode7;
+use crate::ppu::mode7::{M7sel, Mode7};
 use crate::ppu::oam::{Oam, Objsel};
+use crate::ppu::render::ObjPixel;
 use crate::ppu::vram::{VideoPortControl, Vram};
-use crate::scheduler::{Event, PpuEvent, Scheduler};
+use crate::ppu::window::{OBJ_LAYER, Window};
+use crate::scheduler::{DmaEvent, Event, JoypadEvent, PpuEvent, Scheduler};
 use crate::utils::int_traits::ManipulateU16;
 
 mod background;
@@ -17,8 +19,12 @@ mod oam;
 mod render;
 mod tile;
 mod vram;
+mod window;
 
 const SCANLINE_CYCLES: u16 = 1364;
+const FIRST_VISIBLE_DOT: usize = 22;
+
+const PPU1_VERSION: u8 = 1;
 
 const NTSC_SCANLINES: usize = 262;
 const PAL_SCANLINES: usize = 312;
@@ -28,8 +34,8 @@ pub const WIDTH: usize = 256;
 pub const NTSC_HEIGHT: usize = 224;
 pub const PAL_HEIGHT: usize = 239;
 
-//pub const FB_WIDTH: usize = WIDTH << 1;
-//pub const FB_HEIGHT: usize = PAL_HEIGHT << 1;
+pub const FB_WIDTH: usize = WIDTH << 1;
+pub const FB_HEIGHT: usize = PAL_HEIGHT << 1;
 
 bitfield! {
     struct IniDisplay(pub u8) {
@@ -56,6 +62,7 @@ pub struct Ppu {
     mode7: Mode7,
     oam: Oam,
     vram: Vram,
+    window: Window,
     ppu1_mdr: u8,
     ppu2_mdr: u8,
 
@@ -64,7 +71,17 @@ pub struct Ppu {
     pub screen_width: usize,
     pub screen_height: usize,
     pub frame_ready: bool,
-    pub frame_buffer: Box<[[u8; 3]; WIDTH * PAL_HEIGHT]>,
+    pub access_restrictions: bool,
+    pub dot_renderer: bool,
+    drawn_pixels: usize,
+    line_start: u64,
+    access_time: u64,
+    fetched_cgram: [u8; WIDTH],
+    line_sprites: [Option<ObjPixel>; WIDTH],
+    pub frame_buffer: Box<[[u8; 3]; FB_WIDTH * FB_HEIGHT]>,
+    render_buffer: Box<[[u8; 3]; FB_WIDTH * FB_HEIGHT]>,
+    hires_frame: bool,
+    interlaced_frame: bool,
 
     vblank_start: usize,
     vblank_end: usize,
@@ -87,6 +104,7 @@ impl Ppu {
             mode7: Mode7::new(),
             oam: Oam::new(),
             vram: Vram::new(),
+            window: Window::new(),
             ppu1_mdr: 0,
             ppu2_mdr: 0,
             ini_display: IniDisplay(0),
@@ -96,23 +114,48 @@ impl Ppu {
             screen_width: WIDTH,
             screen_height,
             frame_ready: false,
-            frame_buffer: Box::new([[0; 3]; WIDTH * PAL_HEIGHT]),
+            access_restrictions: true,
+            dot_renderer: false,
+            drawn_pixels: 0,
+            line_start: 0,
+            access_time: 0,
+            fetched_cgram: [0; WIDTH],
+            line_sprites: [None; WIDTH],
+            frame_buffer: vec![[0; 3]; FB_WIDTH * FB_HEIGHT]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap(),
+            render_buffer: vec![[0; 3]; FB_WIDTH * FB_HEIGHT]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap(),
+            hires_frame: false,
+            interlaced_frame: false,
         }
     }
 
     pub(crate) fn handle_event(&mut self, scheduler: &mut Scheduler, event: PpuEvent, time: u64) {
-        // TODO H=6, V=0    reload HDMA registers
-        // TODO H=10, V=225 reload OAMADD
         match event {
             PpuEvent::HDraw => {
-                if self.counters.vertical_counter.wrapping_sub(1) < self.screen_height {
-                    self.render_scanline(self.counters.vertical_counter);
+                if self.is_drawing_line() {
+                    self.background
+                        .update_mosaic_line(self.counters.vertical_counter);
+                    if !self.dot_renderer {
+                        self.render_scanline(self.counters.vertical_counter);
+                    }
                 }
                 scheduler.add_event(Event::Ppu(PpuEvent::HBlankStart), time + 1008);
             },
             // H = 274
             PpuEvent::HBlankStart => {
+                if self.dot_renderer && self.is_drawing_line() {
+                    self.draw_pixels_until(WIDTH);
+                }
                 self.counters.set_hblank(true);
+                // H = 278
+                if !self.counters.in_vblank() {
+                    scheduler.add_event(Event::Dma(DmaEvent::HdmaTransfer), time + 16);
+                }
                 scheduler.add_event(
                     Event::Ppu(PpuEvent::NewScanline),
                     time + u64::from(self.counters.hblank_length()),
@@ -122,15 +165,33 @@ impl Ppu {
             PpuEvent::NewScanline => {
                 self.counters.vertical_counter += 1;
                 self.counters.set_hblank(false);
+                self.drawn_pixels = 0;
+                self.line_start = time;
 
                 if self.counters.vertical_counter == self.vblank_end {
                     self.update_vblank_period();
                     self.counters.start_frame();
+                    self.interlaced_frame = self.set_ini.screen_interlacing();
+                    self.background.reset_mosaic();
+                    // H = 6
+                    scheduler.add_event(Event::Dma(DmaEvent::HdmaInit), time + 24);
+                    if !self.ini_display.force_blanking() {
+                        self.oam.clear_flags();
+                    }
                 }
                 self.update_scanline_lenght();
 
-                if self.counters.vertical_counter == self.vblank_start {
+                if self.vblank_starts() {
+                    self.vblank_start = self.counters.vertical_counter;
                     self.counters.enter_vblank();
+                    if !self.ini_display.force_blanking() {
+                        self.oam.reload_addr();
+                    }
+                    // H = 32.5
+                    if self.counters.joypad_auto_read_enabled() {
+                        scheduler.add_event(Event::Joypad(JoypadEvent::AutoReadStart), time + 130);
+                    }
+                    self.present_frame();
                     self.frame_ready = true;
                 }
                 self.counters.check_counters_timer_hit(time);
@@ -139,55 +200,143 @@ impl Ppu {
         }
     }
 
+    fn is_drawing_line(&self) -> bool {
+        self.counters.vertical_counter > 0 && !self.counters.in_vblank()
+    }
+
+    /// Called before each register access at `time`. With the dot renderer
+    /// the current line is drawn up to that H dot before a register changes,
+    /// so raster effects take place in the middle of the line
+    pub(crate) fn catch_up(&mut self, time: u64) {
+        self.access_time = time;
+        if !self.dot_renderer || !self.is_drawing_line() || self.counters.in_hblank() {
+            return;
+        }
+        let h_dot = (time.saturating_sub(self.line_start) / 4) as usize;
+        self.draw_pixels_until(h_dot.saturating_sub(FIRST_VISIBLE_DOT).min(WIDTH));
+    }
+
+    fn draw_pixels_until(&mut self, x: usize) {
+        if x > self.drawn_pixels {
+            self.render_pixels(self.counters.vertical_counter, self.drawn_pixels..x);
+            self.drawn_pixels = x;
+        }
+    }
+
     fn set_ini_write(&mut self, set_ini: SetIni) {
-        self.screen_width <<= usize::from(set_ini.high_res_mode());
-        self.screen_height = if set_ini.overscan_mode() {
-            PAL_HEIGHT
-        } else {
-            NTSC_HEIGHT
-        };
-        self.screen_height <<= usize::from(set_ini.screen_interlacing());
         self.set_ini = set_ini;
     }
 
+    /// Copies the rendered lines to the frame buffer, the frame is
+    /// 512 pixels wide only if a hi-res line was drawn, while
+    /// interlaced frames weave the lines of the last two fields
+    fn present_frame(&mut self) {
+        self.screen_width = if self.hires_frame { FB_WIDTH } else { WIDTH };
+        self.screen_height = (self.vblank_start - 1) << usize::from(self.interlaced_frame);
+
+        for y in 0..self.screen_height {
+            let line = &self.render_buffer[y * FB_WIDTH..(y + 1) * FB_WIDTH];
+            let output = &mut self.frame_buffer[y * self.screen_width..(y + 1) * self.screen_width];
+            if self.hires_frame {
+                output.copy_from_slice(line);
+            } else {
+                for (x, pixel) in output.iter_mut().enumerate() {
+                    *pixel = line[2 * x + 1];
+                }
+            }
+        }
+        self.hires_frame = false;
+    }
+
+    /// VRAM and OAM are only accessible during V-blank or force blank,
+    /// writes during active display are lost and reads return 0
+    fn vram_accessible(&self) -> bool {
+        !self.access_restrictions || self.ini_display.force_blanking() || self.counters.in_vblank()
+    }
+
+    /// CGRAM is busy from the first visible dot to H-blank of the drawn
+    /// lines, returns the column being output at the last access then
+    fn cgram_busy_column(&self) -> Option<usize> {
+        let h_dot = (self.access_time.saturating_sub(self.line_start) / 4) as usize;
+        if self.vram_accessible()
+            || !self.is_drawing_line()
+            || self.counters.in_hblank()
+            || h_dot < FIRST_VISIBLE_DOT
+        {
+            return None;
+        }
+        Some((h_dot - FIRST_VISIBLE_DOT).min(WIDTH - 1))
+    }
+
+    /// Writes to a busy CGRAM land on the entry fetched for the current pixel
+    fn cg_data_write(&mut self, data: u8) {
+        let fetched = self.cgram_busy_column().map(|x| {
+            if self.dot_renderer {
+                self.draw_pixels_until(x + 1);
+            }
+            self.fetched_cgram[x]
+        });
+        self.color.cg_addr_write(data, fetched);
+    }
+
+    fn status77_read(&mut self) -> u8 {
+        self.ppu1_mdr = (u8::from(self.oam.time_over) << 7)
+            | (u8::from(self.oam.range_over) << 6)
+            | (self.ppu1_mdr & 0x10)
+            | PPU1_VERSION;
+        self.ppu1_mdr
+    }
+
     pub fn main_screen_layer_enable(&mut self, data: u8) {
         for idx in 0..4 {
             self.background.backgrounds[idx].enabled_on_main_screen = (data >> idx) & 1 != 0;
         }
         self.oam.enabled_on_main_screen = (data >> 4) & 1 != 0;
     }
+
+    pub fn sub_screen_layer_enable(&mut self, data: u8) {
+        for idx in 0..4 {
+            self.background.backgrounds[idx].enabled_on_sub_screen = (data >> idx) & 1 != 0;
+        }
+        self.oam.enabled_on_sub_screen = (data >> 4) & 1 != 0;
+    }
 }
 
 impl Access for Ppu {
     fn read(&mut self, addr: u16, time: u64) -> Option<u8> {
         match addr.low_byte() {
-            0x34 => Some((self.mode7.do_multiplication()) as u8),
-            0x35 => Some((self.mode7.do_multiplication() >> 8) as u8),
-            0x36 => Some((self.mode7.do_multiplication() >> 16) as u8),
+            // Write-only registers of the PPU1 return its open bus
+            0x04..=0x06 | 0x08..=0x0A | 0x14..=0x16 | 0x18..=0x1A | 0x24..=0x26 | 0x28..=0x2A => {
+                Some(self.ppu1_mdr)
+            },
+            0x34..=0x36 => {
+                let shift = (addr.low_byte() - 0x34) << 3;
+                self.ppu1_mdr = (self.mode7.do_multiplication() >> shift) as u8;
+                Some(self.ppu1_mdr)
+            },
             0x37 => {
                 self.counters.software_latch(time);
                 None
             },
             0x38 => {
-                self.ppu1_mdr = self.oam.oa_addr_read();
+                self.ppu1_mdr = self.oam.oa_addr_read(self.vram_accessible());
                 Some(self.ppu1_mdr)
             },
             0x39 => {
-                self.ppu1_mdr = self.vram.vm_addl_read();
+                self.ppu1_mdr = self.vram.vm_addl_read(self.vram_accessible());
                 Some(self.ppu1_mdr)
             },
             0x3A => {
-                self.ppu1_mdr = self.vram.vm_addh_read();
+                self.ppu1_mdr = self.vram.vm_addh_read(self.vram_accessible());
                 Some(self.ppu1_mdr)
             },
-            0x3B => Some(self.cg_addr_read()),
+            0x3B => Some(self.cg_addr_read(self.cgram_busy_column().is_none())),
             0x3C => Some(self.ophct_read()),
             0x3D => Some(self.opvct_read()),
+            0x3E => Some(self.status77_read()),
             0x3F => Some(self.status78_read()),
-            _ => {
-                println!("Tried to read at {addr:#0x}");
-                Some(self.ppu1_mdr)
-            },
+            // The other write-only registers are not driven and read the CPU open bus
+            _ => None,
         }
     }
 
@@ -198,31 +347,57 @@ impl Access for Ppu {
             0x01 => self.oam.objsel = Objsel(data),
             0x02 => self.oam.oa_addl(data),
             0x03 => self.oam.oa_addh(data),
-            0x04 => self.oam.oa_addr_write(data),
+            0x04 => self.oam.oa_addr_write(data, self.vram_accessible()),
             0x05 => self.background.bg_mode = BgMode(data),
-            0x06 => self.background.mosaic = Mosaic(data),
+            0x06 => self.background.set_mosaic(data),
             0x07..=0x0A => self.background.set_bg_sc(nibble, data),
             0x0B => self.background.set_bg_tileset_addr(0, data),
             0x0C => self.background.set_bg_tileset_addr(2, data),
-            // todo 0D and 0E are also mode 7 regs
-            0x0D | 0x0F | 0x11 | 0x13 => {
+            0x0D => {
+                self.mode7.set_h_offset(data);
+                self.background
+                    .set_bg_h_scroll_offset(nibble, u16::from(data));
+            },
+            0x0E => {
+                self.mode7.set_v_offset(data);
+                self.background
+                    .set_bg_v_scroll_offset(nibble, u16::from(data));
+            },
+            0x0F | 0x11 | 0x13 => {
                 self.background
                     .set_bg_h_scroll_offset(nibble, u16::from(data));
             },
-            0x0E | 0x10 | 0x12 | 0x14 => {
+            0x10 | 0x12 | 0x14 => {
                 self.background
                     .set_bg_v_scroll_offset(nibble, u16::from(data));
             },
             0x15 => self.vram.video_port_control = VideoPortControl(data),
-            0x16 => self.vram.vm_addl(data),
-            0x17 => self.vram.vm_addh(data),
-            0x18 => self.vram.vm_addl_write(data),
-            0x19 => self.vram.vm_addh_write(data),
+            0x16 => self.vram.vm_addl(data, self.vram_accessible()),
+            0x17 => self.vram.vm_addh(data, self.vram_accessible()),
+            0x18 => self.vram.vm_addl_write(data, self.vram_accessible()),
+            0x19 => self.vram.vm_addh_write(data, self.vram_accessible()),
+            0x1A => self.mode7.m7sel = M7sel(data),
             0x1B => self.mode7.set_mode_7_matrix_a(data),
             0x1C => self.mode7.set_mode_7_matrix_b(data),
+            0x1D => self.mode7.set_mode_7_matrix_c(data),
+            0x1E => self.mode7.set_mode_7_matrix_d(data),
+            0x1F => self.mode7.set_center_x(data),
+            0x20 => self.mode7.set_center_y(data),
             0x21 => self.color.cg_addr(data),
-            0x22 => self.color.cg_addr_write(data),
+            0x22 => self.cg_data_write(data),
+            0x23 => self.window.set_mask_settings(0, data),
+            0x24 => self.window.set_mask_settings(2, data),
+            0x25 => self.window.set_mask_settings(OBJ_LAYER, data),
+            0x26 => self.window.set_w1_left(data),
+            0x27 => self.window.set_w1_right(data),
+            0x28 => self.window.set_w2_left(data),
+            0x29 => self.window.set_w2_right(data),
+            0x2A => self.window.set_bg_logic(data),
+            0x2B => self.window.set_obj_color_logic(data),
             0x2C => self.main_screen_layer_enable(data),
+            0x2D => self.sub_screen_layer_enable(data),
+            0x2E => self.window.main_screen_disable = data & 0x1F,
+            0x2F => self.window.sub_screen_disable = data & 0x1F,
             0x30 => self.color.cgwsel = Cgwsel(data),
             0x31 => self.color.cgadsub = Cgadsub(data),
             0x32 => self.color.color_data_write(&ColorData(data)),
@@ -9,13 +9,25 @@ bitfield! {
     }
 }
 
+impl BgMode {
+    pub fn is_hires(&self) -> bool {
+        matches!(self.bg_mode(), 5 | 6)
+    }
+
+    /// Whether the background uses 16x16 tiles instead of 8x8
+    pub fn large_tiles(&self, idx: usize) -> bool {
+        match idx {
+            0 => self.bg1_tile_size(),
+            1 => self.bg2_tile_size(),
+            2 => self.bg3_tile_size(),
+            _ => self.bg4_tile_size(),
+        }
+    }
+}
+
 bitfield! {
-    #[allow(dead_code)]
     pub struct Mosaic(pub u8) {
-        bg1_enabled: bool @ 0,
-        bg2_enabled: bool @ 1,
-        bg3_enabled: bool @ 2,
-        bg4_enabled: bool @ 3,
+        bg_enabled: u8 @ 0..=3,
         mosaic_size: u8 @ 4..=7,
     }
 }
@@ -36,9 +48,14 @@ pub struct Bg {
     pub bg_hofs: u16,
     pub bg_vofs: u16,
     pub enabled_on_main_screen: bool,
+    pub enabled_on_sub_screen: bool,
 }
 
 impl Bg {
+    pub fn is_enabled(self) -> bool {
+        self.enabled_on_main_screen || self.enabled_on_sub_screen
+    }
+
     pub fn tile_map_addr(self, tile_x: usize, tile_y: usize) -> usize {
         let tilemap_idx = match (self.bg_sc.h_tilemap_count(), self.bg_sc.v_tilemap_count()) {
             (true, true) => (tile_x / 32) % 2 + ((tile_y / 32) % 2) * 2,
@@ -55,7 +72,9 @@ impl Bg {
 pub(super) struct Background {
     pub backgrounds: [Bg; 4],
     pub bg_mode: BgMode,
-    pub mosaic: Mosaic,
+    mosaic: Mosaic,
+    mosaic_counter: usize,
+    mosaic_line: usize,
     h_offset_latch: u16,
     offset_latch: u16,
 }
@@ -69,14 +88,55 @@ impl Background {
                 bg_hofs: 0,
                 bg_vofs: 0,
                 enabled_on_main_screen: false,
+                enabled_on_sub_screen: false,
             }; 4],
             bg_mode: BgMode(0),
             mosaic: Mosaic(0),
+            mosaic_counter: 0,
+            mosaic_line: 0,
             h_offset_latch: 0,
             offset_latch: 0,
         }
     }
 
+    /// Writing MOSAIC also restarts the vertical blocks from the next line
+    pub fn set_mosaic(&mut self, data: u8) {
+        self.mosaic = Mosaic(data);
+        self.reset_mosaic();
+    }
+
+    /// The vertical mosaic counter restarts with each frame
+    pub fn reset_mosaic(&mut self) {
+        self.mosaic_counter = 0;
+    }
+
+    /// Latches the line sampled by vertical mosaic, it advances once
+    /// every `mosaic_size` lines
+    pub fn update_mosaic_line(&mut self, screen_y: usize) {
+        if self.mosaic_counter == 0 {
+            self.mosaic_counter = usize::from(self.mosaic.mosaic_size()) + 1;
+            self.mosaic_line = screen_y;
+        }
+        self.mosaic_counter -= 1;
+    }
+
+    /// Size of the mosaic blocks of a background, 1 when disabled
+    pub fn mosaic_block(&self, idx: usize) -> usize {
+        if self.mosaic.bg_enabled() & (1 << idx) != 0 {
+            usize::from(self.mosaic.mosaic_size()) + 1
+        } else {
+            1
+        }
+    }
+
+    pub fn bg_line(&self, idx: usize, screen_y: usize) -> usize {
+        if self.mosaic_block(idx) > 1 {
+            self.mosaic_line
+        } else {
+            screen_y
+        }
+    }
+
     pub fn set_bg_sc(&mut self, addr_low_byte: usize, data: u8) {
         let idx = addr_low_byte - 0x07;
         self.backgrounds[idx].bg_sc = BgSc(data);
@@ -101,3 +161,72 @@ impl Background {
         self.offset_latch = data;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bg(bg_sc: u8) -> Bg {
+        let mut background = Background::new();
+        background.set_bg_sc(0x07, bg_sc);
+        background.backgrounds[0]
+    }
+
+    #[test]
+    fn mosaic_blocks_only_apply_to_enabled_backgrounds() {
+        let mut background = Background::new();
+        background.set_mosaic(0x35);
+        assert_eq!(background.mosaic_block(0), 4);
+        assert_eq!(background.mosaic_block(1), 1);
+        assert_eq!(background.mosaic_block(2), 4);
+    }
+
+    #[test]
+    fn mosaic_write_restarts_the_vertical_blocks() {
+        let mut background = Background::new();
+        background.set_mosaic(0x31);
+        let mut lines = Vec::new();
+        for screen_y in 1..=10 {
+            if screen_y == 7 {
+                background.set_mosaic(0x31);
+            }
+            background.update_mosaic_line(screen_y);
+            lines.push(background.bg_line(0, screen_y));
+        }
+        assert_eq!(lines, [1, 1, 1, 1, 5, 5, 7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn tilemap_32x32_wraps_around() {
+        let bg = bg(0x04);
+        assert_eq!(bg.tile_map_addr(0, 0), 0x400);
+        assert_eq!(bg.tile_map_addr(31, 31), 0x7FF);
+        assert_eq!(bg.tile_map_addr(32, 32), 0x400);
+    }
+
+    #[test]
+    fn tilemap_64x32_places_screens_side_by_side() {
+        let bg = bg(0x05);
+        assert_eq!(bg.tile_map_addr(32, 0), 0x800);
+        assert_eq!(bg.tile_map_addr(0, 32), 0x400);
+        assert_eq!(bg.tile_map_addr(64, 0), 0x400);
+    }
+
+    #[test]
+    fn tilemap_32x64_stacks_screens() {
+        let bg = bg(0x06);
+        assert_eq!(bg.tile_map_addr(32, 0), 0x400);
+        assert_eq!(bg.tile_map_addr(0, 32), 0x800);
+        assert_eq!(bg.tile_map_addr(5, 63), 0x800 + 31 * 32 + 5);
+    }
+
+    #[test]
+    fn tilemap_64x64_uses_four_screens() {
+        let bg = bg(0x07);
+        assert_eq!(bg.tile_map_addr(0, 0), 0x400);
+        assert_eq!(bg.tile_map_addr(32, 0), 0x800);
+        assert_eq!(bg.tile_map_addr(0, 32), 0xC00);
+        assert_eq!(bg.tile_map_addr(32, 32), 0x1000);
+        assert_eq!(bg.tile_map_addr(64, 64), 0x400);
+    }
+}
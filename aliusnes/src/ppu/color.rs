@@ -5,26 +5,36 @@ use crate::utils::int_traits::ManipulateU16;
 bitfield! {
     pub struct Cgwsel(pub u8) {
         direct_color_mode: bool @ 0,
-        addend_is_sub_screen: bool @ 1,
-        sub_screen_transparent_region: u8 @ 4..=5,
-        main_screen_black_region: u8 @ 6..=7,
+        pub addend_is_sub_screen: bool @ 1,
+        pub sub_screen_transparent_region: u8 @ 4..=5,
+        pub main_screen_black_region: u8 @ 6..=7,
     }
 }
 
 bitfield! {
-    #[allow(dead_code)]
     pub struct Cgadsub(pub u8) {
         bg1_color_math_enabled: bool @ 0,
         bg2_color_math_enabled: bool @ 1,
         bg3_color_math_enabled: bool @ 2,
         bg4_color_math_enabled: bool @ 3,
-        obj_color_math_enabled: bool @ 4,
-        backdrop_color_math_enabled: bool @ 5,
-        halve_color_math_result: bool @ 6,
+        pub obj_color_math_enabled: bool @ 4,
+        pub backdrop_color_math_enabled: bool @ 5,
+        pub halve_color_math_result: bool @ 6,
         operation_is_sub: bool @ 7,
     }
 }
 
+impl Cgadsub {
+    pub fn bg_color_math_enabled(&self, idx: usize) -> bool {
+        match idx {
+            0 => self.bg1_color_math_enabled(),
+            1 => self.bg2_color_math_enabled(),
+            2 => self.bg3_color_math_enabled(),
+            _ => self.bg4_color_math_enabled(),
+        }
+    }
+}
+
 bitfield! {
     pub struct ColorData(pub u8) {
         val: u8 @ 0..=4,
@@ -103,12 +113,12 @@ const FIVEBIT_TO_EIGHTBIT_LUT: [[u8; 0x20]; 0x10] = [
 
 pub(super) struct Color {
     pub cgram: [u16; 0x100],
-    cg_addr: u8,
-    latch: Option<u8>,
+    cg_addr: u16,
+    latch: u8,
 
     pub cgwsel: Cgwsel,
     pub cgadsub: Cgadsub,
-    fixed_color: u16,
+    pub fixed_color: u16,
 }
 
 impl Color {
@@ -116,27 +126,29 @@ impl Color {
         Self {
             cgram: [0; 0x100],
             cg_addr: 0x00,
-            latch: None,
+            latch: 0x00,
             cgwsel: Cgwsel(0),
             cgadsub: Cgadsub(0),
             fixed_color: 0,
         }
     }
 
+    /// The address points to a byte, its lowest bit selects the low or
+    /// high half of the color for both reads and writes
     pub fn cg_addr(&mut self, data: u8) {
-        self.cg_addr = data;
-        self.latch = None;
-    }
-
-    pub fn cg_addr_write(&mut self, data: u8) {
-        match self.latch {
-            None => self.latch = Some(data),
-            Some(byte) => {
-                self.cgram[self.cg_addr as usize] = u16::from(byte) | (u16::from(data) << 8);
-                self.cg_addr = self.cg_addr.wrapping_add(1);
-                self.latch = None;
-            },
+        self.cg_addr = u16::from(data) << 1;
+    }
+
+    /// While a line is drawn the color is stored to the `fetched` entry,
+    /// the one the PPU is reading to output the current pixel
+    pub fn cg_addr_write(&mut self, data: u8, fetched: Option<u8>) {
+        if self.cg_addr & 1 == 0 {
+            self.latch = data;
+        } else {
+            let entry = fetched.map_or(usize::from(self.cg_addr >> 1), usize::from);
+            self.cgram[entry] = u16::from(self.latch) | (u16::from(data & 0x7F) << 8);
         }
+        self.cg_addr = (self.cg_addr + 1) & 0x1FF;
     }
 
     pub fn color_data_write(&mut self, color_data: &ColorData) {
@@ -153,23 +165,54 @@ impl Color {
         }
     }
 
-    pub fn pixel_color<T: BitPlane, const BG_MODE: u8>(
-        &self,
-        bg_index: u8,
-        palette: u8,
-        index: u8,
-    ) -> u16 {
+    /// Cgram entry of a background pixel
+    pub fn bg_entry<T: BitPlane, const BG_MODE: u8>(bg_index: u8, palette: u8, index: u8) -> u8 {
         let starting_palette_entry = match T::PLANES {
             2 if BG_MODE == 0 => bg_index * 32 + (palette * T::NUM_COLORS),
             8 => 0,
             _ => palette * T::NUM_COLORS,
         };
+        starting_palette_entry + index
+    }
 
-        if T::PLANES == 8 && self.cgwsel.direct_color_mode() {
+    pub fn pixel_color<T: BitPlane, const BG_MODE: u8>(
+        &self,
+        bg_index: u8,
+        palette: u8,
+        index: u8,
+    ) -> u16 {
+        // Only BG1 can use direct color, EXTBG pixels on BG2 always index cgram
+        if T::PLANES == 8 && bg_index == 0 && self.cgwsel.direct_color_mode() {
             Self::direct_color(palette, index)
         } else {
-            self.cgram[(starting_palette_entry + index) as usize]
+            self.cgram[usize::from(Self::bg_entry::<T, BG_MODE>(bg_index, palette, index))]
+        }
+    }
+
+    /// Adds or subtracts `addend` channel by channel, clamping the result
+    pub fn blend(&self, color: u16, addend: u16, halve: bool) -> u16 {
+        let mut result = 0;
+        for shift in [0, 5, 10] {
+            let a = (color >> shift) & 0x1F;
+            let b = (addend >> shift) & 0x1F;
+            let channel = if self.cgadsub.operation_is_sub() {
+                a.saturating_sub(b)
+            } else {
+                a + b
+            };
+            let channel = if halve { channel >> 1 } else { channel };
+            result |= channel.min(0x1F) << shift;
         }
+        result
+    }
+
+    /// Sprites use the upper half of cgram, palettes 8 to 15
+    pub fn obj_entry(palette: u8, index: u8) -> u8 {
+        128 + palette * 16 + index
+    }
+
+    pub fn obj_color(&self, palette: u8, index: u8) -> u16 {
+        self.cgram[usize::from(Self::obj_entry(palette, index))]
     }
 
     /// palette: 3 bit from a tilemap entry that is treated as bgr
@@ -186,18 +229,20 @@ impl Color {
 }
 
 impl Ppu {
-    pub fn cg_addr_read(&mut self) -> u8 {
-        if let Some(high_byte) = self.color.latch {
-            self.color.cg_addr = self.color.cg_addr.wrapping_add(1);
-            self.color.latch = None;
-            self.ppu2_mdr = (high_byte & 0x7F) | (self.ppu2_mdr & 0x80);
-            self.ppu2_mdr
+    /// Bit 7 of the high byte is not stored and reads the PPU2 open bus
+    pub fn cg_addr_read(&mut self, accessible: bool) -> u8 {
+        let val = if accessible {
+            self.color.cgram[usize::from(self.color.cg_addr >> 1)]
         } else {
-            let val = self.color.cgram[self.color.cg_addr as usize];
-            self.color.latch = Some(val.high_byte());
-            self.ppu2_mdr = val.low_byte();
-            self.ppu2_mdr
-        }
+            0
+        };
+        self.ppu2_mdr = if self.color.cg_addr & 1 == 0 {
+            val.low_byte()
+        } else {
+            (val.high_byte() & 0x7F) | (self.ppu2_mdr & 0x80)
+        };
+        self.color.cg_addr = (self.color.cg_addr + 1) & 0x1FF;
+        self.ppu2_mdr
     }
 
     pub fn rgb555_to_rgb888(&self, value: u16) -> [u8; 3] {
@@ -208,3 +253,86 @@ impl Ppu {
         [r, g, b]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Access;
+    use crate::cart::info::Model;
+    use crate::ppu::tile::{Bpp4, Bpp8};
+
+    fn color_with_direct_mode() -> Color {
+        let mut color = Color::new();
+        color.cgwsel = Cgwsel(0x01);
+        color.cgram.fill(0x1234);
+        color
+    }
+
+    #[test]
+    fn direct_color_builds_rgb555_from_pixel_and_palette() {
+        let color = color_with_direct_mode();
+        // BBGGGRRR with the palette bits as the next lower bit of each channel
+        assert_eq!(color.pixel_color::<Bpp8, 3>(0, 0, 0x07), 0x001C);
+        assert_eq!(color.pixel_color::<Bpp8, 3>(0, 1, 0x07), 0x001E);
+        assert_eq!(color.pixel_color::<Bpp8, 4>(0, 2, 0x38), 0x03C0);
+        assert_eq!(color.pixel_color::<Bpp8, 7>(0, 0, 0xC0), 0x6000);
+        assert_eq!(color.pixel_color::<Bpp8, 3>(0, 7, 0xFF), 0x73DE);
+    }
+
+    #[test]
+    fn direct_color_only_applies_to_bg1_256_colors() {
+        let color = color_with_direct_mode();
+        assert_eq!(color.pixel_color::<Bpp8, 7>(1, 0, 0x07), 0x1234);
+        assert_eq!(color.pixel_color::<Bpp4, 3>(1, 0, 0x07), 0x1234);
+    }
+
+    #[test]
+    fn cgram_reads_alternate_bytes_and_keep_open_bus_bit() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.access_restrictions = false;
+        ppu.write(0x2121, 0x10);
+        for byte in [0x34, 0xD2, 0xFF, 0xFF] {
+            ppu.write(0x2122, byte);
+        }
+
+        ppu.write(0x2121, 0x10);
+        ppu.ppu2_mdr = 0x80;
+        assert_eq!(ppu.read(0x213B, 0), Some(0x34));
+        assert_eq!(ppu.read(0x213B, 0), Some(0x52));
+        assert_eq!(ppu.read(0x213B, 0), Some(0xFF));
+        assert_eq!(ppu.read(0x213B, 0), Some(0xFF));
+    }
+
+    #[test]
+    fn cgram_address_write_resets_the_byte_select() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.access_restrictions = false;
+        ppu.write(0x2121, 0x01);
+        ppu.write(0x2122, 0xAA);
+        ppu.write(0x2121, 0x01);
+        ppu.write(0x2122, 0x1F);
+        ppu.write(0x2122, 0x00);
+        assert_eq!(ppu.color.cgram[1], 0x001F);
+    }
+
+    #[test]
+    fn cgram_reads_need_blanking() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        // In the middle of the first drawn line
+        ppu.counters.vertical_counter = 1;
+        ppu.catch_up(4 * 100);
+
+        ppu.color.cgram[1] = 0x1234;
+        ppu.write(0x2121, 0x01);
+        assert_eq!(ppu.read(0x213B, 0), Some(0x00));
+        assert_eq!(ppu.read(0x213B, 0), Some(0x00));
+
+        ppu.counters.set_hblank(true);
+        ppu.write(0x2121, 0x01);
+        assert_eq!(ppu.read(0x213B, 0), Some(0x34));
+        ppu.write(0x2121, 0x01);
+        ppu.write(0x2122, 0x1F);
+        ppu.write(0x2122, 0x00);
+        assert_eq!(ppu.color.cgram[1], 0x001F);
+    }
+}
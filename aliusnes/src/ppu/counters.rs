@@ -83,6 +83,10 @@ impl Counters {
         }
     }
 
+    pub(super) fn odd_frame(&self) -> bool {
+        self.stat78.odd_frame()
+    }
+
     pub(crate) fn set_hblank(&mut self, in_hblank: bool) {
         self.hv_status.set_in_hblank(in_hblank);
     }
@@ -143,6 +147,18 @@ impl Counters {
         }
     }
 
+    pub(crate) fn joypad_auto_read_enabled(&self) -> bool {
+        self.nmitimen.joypad_enable()
+    }
+
+    pub(crate) fn in_hblank(&self) -> bool {
+        self.hv_status.in_hblank()
+    }
+
+    pub(crate) fn in_vblank(&self) -> bool {
+        self.hv_status.in_vblank()
+    }
+
     pub(crate) fn enter_vblank(&mut self) {
         self.hv_status.set_in_vblank(true);
         self.rdnmi.set_in_nmi(true);
@@ -193,9 +209,7 @@ impl Ppu {
     }
 
     pub fn write_nmitien(&mut self, val: u8) {
-        let nmitimen = Nmitimen(val);
-        let _joypad_enable = nmitimen.joypad_enable(); // todo when implementing joypad
-        self.counters.nmitimen = nmitimen;
+        self.counters.nmitimen = Nmitimen(val);
         // self.counters.check_counters_timer_hit();
     }
 
@@ -250,12 +264,17 @@ impl Ppu {
         }
     }
 
+    /// V-blank starts at line 225, unless overscan is set when that line
+    /// is reached, in which case it is delayed to line 240
+    pub(super) fn vblank_starts(&self) -> bool {
+        match self.counters.vertical_counter {
+            v if v == NTSC_HEIGHT + 1 => !self.set_ini.overscan_mode(),
+            v if v == PAL_HEIGHT + 1 => !self.counters.in_vblank(),
+            _ => false,
+        }
+    }
+
     pub(super) fn update_vblank_period(&mut self) {
-        self.vblank_start = if self.set_ini.overscan_mode() {
-            PAL_HEIGHT
-        } else {
-            NTSC_HEIGHT
-        } + 1;
         self.vblank_end =
             if self.counters.stat78.is_pal() {
                 PAL_SCANLINES
@@ -264,3 +283,43 @@ impl Ppu {
             } + usize::from(self.set_ini.screen_interlacing() && !self.counters.stat78.odd_frame());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cart::info::Model;
+    use crate::ppu::SetIni;
+
+    fn vblank_lines(ppu: &mut Ppu, overscan_from: Option<usize>) -> Vec<usize> {
+        let mut lines = vec![];
+        for v in 1..NTSC_SCANLINES {
+            ppu.counters.vertical_counter = v;
+            if overscan_from == Some(v) {
+                ppu.set_ini = SetIni(0x04);
+            }
+            if ppu.vblank_starts() {
+                ppu.counters.enter_vblank();
+                lines.push(v);
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn vblank_starts_after_line_224() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        assert_eq!(vblank_lines(&mut ppu, None), [NTSC_HEIGHT + 1]);
+    }
+
+    #[test]
+    fn overscan_set_before_line_225_delays_vblank() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        assert_eq!(vblank_lines(&mut ppu, Some(100)), [PAL_HEIGHT + 1]);
+    }
+
+    #[test]
+    fn overscan_set_after_line_225_applies_next_frame() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        assert_eq!(vblank_lines(&mut ppu, Some(230)), [NTSC_HEIGHT + 1]);
+    }
+}
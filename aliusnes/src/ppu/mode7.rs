@@ -1,29 +1,128 @@
+bitfield! {
+    #[derive(Clone, Copy)]
+    pub struct M7sel(pub u8) {
+        pub flip_horizontal: bool @ 0,
+        pub flip_vertical: bool @ 1,
+        pub screen_over: u8 @ 6..=7,
+    }
+}
+
 pub struct Mode7 {
+    pub m7sel: M7sel,
     matrix_a: i16,
     matrix_b: i16,
+    matrix_c: i16,
+    matrix_d: i16,
+    center_x: i16,
+    center_y: i16,
+    h_offset: i16,
+    v_offset: i16,
     mode_7_latch: u8,
 }
 
 impl Mode7 {
     pub fn new() -> Self {
         Self {
+            m7sel: M7sel(0),
             matrix_a: 0,
             matrix_b: 0,
+            matrix_c: 0,
+            matrix_d: 0,
+            center_x: 0,
+            center_y: 0,
+            h_offset: 0,
+            v_offset: 0,
             mode_7_latch: 0,
         }
     }
 
-    pub fn set_mode_7_matrix_a(&mut self, data: u8) {
-        self.matrix_a = (i16::from(data) << 8) | i16::from(self.mode_7_latch);
+    fn latched_word(&mut self, data: u8) -> i16 {
+        let value = (i16::from(data) << 8) | i16::from(self.mode_7_latch);
         self.mode_7_latch = data;
+        value
+    }
+
+    /// Centre and scroll registers are 13 bit signed values
+    fn latched_13_bit(&mut self, data: u8) -> i16 {
+        (self.latched_word(data) << 3) >> 3
+    }
+
+    pub fn set_mode_7_matrix_a(&mut self, data: u8) {
+        self.matrix_a = self.latched_word(data);
     }
 
     pub fn set_mode_7_matrix_b(&mut self, data: u8) {
-        self.matrix_b = (i16::from(data) << 8) | i16::from(self.mode_7_latch);
-        self.mode_7_latch = data;
+        self.matrix_b = self.latched_word(data);
+    }
+
+    pub fn set_mode_7_matrix_c(&mut self, data: u8) {
+        self.matrix_c = self.latched_word(data);
     }
 
+    pub fn set_mode_7_matrix_d(&mut self, data: u8) {
+        self.matrix_d = self.latched_word(data);
+    }
+
+    pub fn set_center_x(&mut self, data: u8) {
+        self.center_x = self.latched_13_bit(data);
+    }
+
+    pub fn set_center_y(&mut self, data: u8) {
+        self.center_y = self.latched_13_bit(data);
+    }
+
+    pub fn set_h_offset(&mut self, data: u8) {
+        self.h_offset = self.latched_13_bit(data);
+    }
+
+    pub fn set_v_offset(&mut self, data: u8) {
+        self.v_offset = self.latched_13_bit(data);
+    }
+
+    /// Signed 16 bit matrix A times the last byte written to matrix B
     pub fn do_multiplication(&self) -> i32 {
-        i32::from(self.matrix_a) * i32::from(self.matrix_b & 0xFF)
+        i32::from(self.matrix_a) * i32::from((self.matrix_b >> 8) as i8)
+    }
+
+    /// Returns the position in the 1024x1024 playfield of the leftmost
+    /// pixel of a scanline, with 8 fractional bits
+    pub fn scanline_origin(&self, screen_y: usize) -> (i32, i32) {
+        fn clip(value: i32) -> i32 {
+            if value & 0x2000 != 0 {
+                value | !0x3FF
+            } else {
+                value & 0x3FF
+            }
+        }
+
+        let y = if self.m7sel.flip_vertical() {
+            255 - (screen_y as i32 & 0xFF)
+        } else {
+            screen_y as i32 & 0xFF
+        };
+        let (a, b) = (i32::from(self.matrix_a), i32::from(self.matrix_b));
+        let (c, d) = (i32::from(self.matrix_c), i32::from(self.matrix_d));
+        let (center_x, center_y) = (i32::from(self.center_x), i32::from(self.center_y));
+        let h_offset = clip(i32::from(self.h_offset) - center_x);
+        let v_offset = clip(i32::from(self.v_offset) - center_y);
+
+        let origin_x =
+            ((a * h_offset) & !63) + ((b * v_offset) & !63) + ((b * y) & !63) + (center_x << 8);
+        let origin_y =
+            ((c * h_offset) & !63) + ((d * v_offset) & !63) + ((d * y) & !63) + (center_y << 8);
+        (origin_x, origin_y)
+    }
+
+    /// Integer playfield coordinates of `screen_x` along a scanline
+    pub fn pixel_coords(&self, (origin_x, origin_y): (i32, i32), screen_x: usize) -> (i32, i32) {
+        let x = if self.m7sel.flip_horizontal() {
+            255 - screen_x as i32
+        } else {
+            screen_x as i32
+        };
+        (
+            (origin_x + i32::from(self.matrix_a) * x) >> 8,
+            (origin_y + i32::from(self.matrix_c) * x) >> 8,
+        )
     }
 }
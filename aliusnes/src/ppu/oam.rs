@@ -1,7 +1,9 @@
 use crate::utils::int_traits::ManipulateU16;
 
+const MAX_SPRITES_PER_LINE: usize = 32;
+const MAX_SLIVERS_PER_LINE: usize = 34;
+
 bitfield! {
-    #[allow(dead_code)]
     #[derive(Clone, Copy)]
     pub(super) struct Objsel(pub u8) {
         name_base_addr: u8 @ 0..=2,
@@ -10,13 +12,82 @@ bitfield! {
     }
 }
 
+impl Objsel {
+    /// Returns the (width, height) of small and large sprites
+    pub fn sprite_sizes(self) -> [(u16, u16); 2] {
+        match self.object_size() {
+            0 => [(8, 8), (16, 16)],
+            1 => [(8, 8), (32, 32)],
+            2 => [(8, 8), (64, 64)],
+            3 => [(16, 16), (32, 32)],
+            4 => [(16, 16), (64, 64)],
+            5 => [(32, 32), (64, 64)],
+            6 => [(16, 32), (32, 64)],
+            7 => [(16, 32), (32, 32)],
+            _ => unreachable!(),
+        }
+    }
+
+    /// Word address in vram of the 8x8 tile `name`, a 9 bit index
+    /// where the highest bit selects the second name table
+    pub fn tile_addr(self, name: u16) -> u16 {
+        let base = u16::from(self.name_base_addr()) << 13;
+        let table_offset = if name & 0x100 != 0 {
+            (u16::from(self.name_select()) + 1) << 12
+        } else {
+            0
+        };
+        (base + table_offset + ((name & 0xFF) << 4)) & 0x7FFF
+    }
+}
+
+bitfield! {
+    #[derive(Clone, Copy)]
+    pub(super) struct ObjAttributes(pub u8) {
+        pub name_table: bool @ 0,
+        pub palette: u8 @ 1..=3,
+        pub priority: u8 @ 4..=5,
+        pub flip_horizontal: bool @ 6,
+        pub flip_vertical: bool @ 7,
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct Sprite {
+    pub x: u16,
+    pub y: u8,
+    pub name: u16,
+    pub attributes: ObjAttributes,
+    pub large: bool,
+}
+
+impl Sprite {
+    /// X coordinate as a 9 bit signed value
+    pub fn screen_x(&self) -> i16 {
+        ((self.x << 7) as i16) >> 7
+    }
+}
+
+/// An 8 pixels wide slice of a sprite row, the unit fetched by the PPU
+#[derive(Clone, Copy)]
+pub(super) struct Sliver {
+    pub sprite: Sprite,
+    pub width: u16,
+    pub col: u16,
+    pub row: u16,
+}
+
 pub(super) struct Oam {
     ram: [u8; 0x220],
     pub(super) objsel: Objsel,
     oa_addr: u16,
     internal_addr: u16,
     latch: u8,
+    priority_rotation: bool,
+    pub range_over: bool,
+    pub time_over: bool,
     pub enabled_on_main_screen: bool,
+    pub enabled_on_sub_screen: bool,
 }
 
 impl Oam {
@@ -27,7 +98,11 @@ impl Oam {
             oa_addr: 0x0000,
             internal_addr: 0x0000,
             latch: 0x00,
+            priority_rotation: false,
+            range_over: false,
+            time_over: false,
             enabled_on_main_screen: false,
+            enabled_on_sub_screen: false,
         }
     }
 
@@ -37,11 +112,31 @@ impl Oam {
     }
 
     pub fn oa_addh(&mut self, data: u8) {
-        self.oa_addr.set_high_byte(data);
+        self.oa_addr.set_high_byte(data & 1);
+        self.priority_rotation = data & 0x80 != 0;
+        self.internal_addr = (self.oa_addr & 0x1FF) << 1;
+    }
+
+    /// The internal address is reloaded from OAMADD at the start of V-blank
+    pub fn reload_addr(&mut self) {
         self.internal_addr = (self.oa_addr & 0x1FF) << 1;
     }
 
-    pub fn oa_addr_write(&mut self, data: u8) {
+    /// The 32 bytes of the high table are mirrored up to the end
+    /// of the 10-bit address space
+    fn ram_index(&self) -> usize {
+        if self.internal_addr >= 0x200 {
+            0x200 | (self.internal_addr as usize & 0x1F)
+        } else {
+            self.internal_addr as usize
+        }
+    }
+
+    pub fn oa_addr_write(&mut self, data: u8, accessible: bool) {
+        if !accessible {
+            self.internal_addr = (self.internal_addr + 1) & 0x3FF;
+            return;
+        }
         if (self.internal_addr & 1) == 0 {
             self.latch = data;
         }
@@ -50,14 +145,266 @@ impl Oam {
             self.ram[self.internal_addr as usize] = data;
         }
         if self.internal_addr >= 0x200 {
-            self.ram[self.internal_addr as usize] = data;
+            self.ram[self.ram_index()] = data;
         }
-        self.internal_addr = (self.internal_addr + 1) % 0x220;
+        self.internal_addr = (self.internal_addr + 1) & 0x3FF;
     }
 
-    pub fn oa_addr_read(&mut self) -> u8 {
-        let result = self.ram[self.internal_addr as usize];
-        self.internal_addr = (self.internal_addr + 1) % 0x220;
+    pub fn oa_addr_read(&mut self, accessible: bool) -> u8 {
+        let result = if accessible {
+            self.ram[self.ram_index()]
+        } else {
+            0
+        };
+        self.internal_addr = (self.internal_addr + 1) & 0x3FF;
         result
     }
+
+    /// Decodes one of the 128 sprites, combining its 4 bytes entry
+    /// with the 2 bits stored in the high table
+    pub fn sprite(&self, index: usize) -> Sprite {
+        let entry = &self.ram[index * 4..index * 4 + 4];
+        let high_bits = self.ram[0x200 + (index >> 2)] >> ((index & 3) << 1);
+        let attributes = ObjAttributes(entry[3]);
+
+        Sprite {
+            x: u16::from(entry[0]) | (u16::from(high_bits & 1) << 8),
+            y: entry[1],
+            name: u16::from(entry[2]) | (u16::from(attributes.name_table()) << 8),
+            attributes,
+            large: high_bits & 2 != 0,
+        }
+    }
+
+    /// Collects up to 32 sprites that intersect `line`, scanning OAM from
+    /// the priority rotation sprite, then loads up to 34 slivers from them
+    /// starting with the last sprite found as the PPU does.
+    /// With OBJ interlace sprites are half as tall on screen and
+    /// `interlace_field` selects the even or odd rows
+    pub fn evaluate_line(&mut self, line: u8, interlace_field: Option<bool>) -> Vec<Sliver> {
+        let sizes = self.objsel.sprite_sizes();
+        let first_sprite = if self.priority_rotation {
+            usize::from(self.oa_addr >> 1) & 0x7F
+        } else {
+            0
+        };
+
+        let mut in_range = Vec::with_capacity(MAX_SPRITES_PER_LINE);
+        for offset in 0..128 {
+            let sprite = self.sprite((first_sprite + offset) & 0x7F);
+            let (width, height) = sizes[usize::from(sprite.large)];
+            let height = height >> usize::from(interlace_field.is_some());
+
+            if u16::from(line.wrapping_sub(sprite.y)) >= height {
+                continue;
+            }
+            // A sprite at X = 256 is still considered in range
+            if sprite.x > 256 && sprite.x + width - 1 < 512 {
+                continue;
+            }
+            if in_range.len() == MAX_SPRITES_PER_LINE {
+                self.range_over = true;
+                break;
+            }
+            in_range.push(sprite);
+        }
+
+        let mut slivers = Vec::with_capacity(MAX_SLIVERS_PER_LINE);
+        for sprite in in_range.into_iter().rev() {
+            let (width, height) = sizes[usize::from(sprite.large)];
+
+            let mut row = u16::from(line.wrapping_sub(sprite.y));
+            if let Some(odd_field) = interlace_field {
+                row = (row << 1) | u16::from(odd_field);
+            }
+            if sprite.attributes.flip_vertical() {
+                // Rectangular sprites flip their two square halves separately
+                row = if width == height {
+                    height - 1 - row
+                } else if row < width {
+                    width - 1 - row
+                } else {
+                    width + (width - 1) - (row - width)
+                };
+            }
+
+            for col in (0..width).step_by(8) {
+                let screen_x = sprite.screen_x() + col as i16;
+                if !(-7..256).contains(&screen_x) {
+                    continue;
+                }
+                if slivers.len() == MAX_SLIVERS_PER_LINE {
+                    self.time_over = true;
+                    return slivers;
+                }
+                slivers.push(Sliver {
+                    sprite,
+                    width,
+                    col,
+                    row,
+                });
+            }
+        }
+        slivers
+    }
+
+    pub fn clear_flags(&mut self) {
+        self.range_over = false;
+        self.time_over = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oam_with_sprites(sprites: &[(u16, u8)], objsel: u8, large: bool) -> Oam {
+        let mut oam = Oam::new();
+        oam.objsel = Objsel(objsel);
+        oam.oa_addl(0);
+        oam.oa_addh(0);
+        for index in 0..128 {
+            let (x, y) = sprites.get(index).copied().unwrap_or((0, 0xF0));
+            for byte in [x as u8, y, index as u8, 0] {
+                oam.oa_addr_write(byte, true);
+            }
+        }
+        for index in (0..128).step_by(4) {
+            let mut high_table = 0;
+            for sub in 0..4 {
+                let x = sprites.get(index + sub).map_or(0, |sprite| sprite.0);
+                high_table |= (u8::from(x > 0xFF) | (u8::from(large) << 1)) << (sub * 2);
+            }
+            oam.oa_addr_write(high_table, true);
+        }
+        oam
+    }
+
+    #[test]
+    fn range_over_keeps_first_32_sprites() {
+        let sprites: Vec<_> = (0..33).map(|i| (i * 7, 10)).collect();
+        let mut oam = oam_with_sprites(&sprites, 0, false);
+
+        let slivers = oam.evaluate_line(12, None);
+
+        assert!(oam.range_over);
+        assert!(!oam.time_over);
+        assert_eq!(slivers.len(), 32);
+        assert_eq!(slivers.first().unwrap().sprite.name, 31);
+        assert_eq!(slivers.last().unwrap().sprite.name, 0);
+    }
+
+    #[test]
+    fn time_over_drops_highest_priority_slivers() {
+        // 18 sprites 16 pixels wide need 36 slivers
+        let sprites: Vec<_> = (0..18).map(|i| (i * 12, 10)).collect();
+        let mut oam = oam_with_sprites(&sprites, 0, true);
+
+        let slivers = oam.evaluate_line(12, None);
+
+        assert!(!oam.range_over);
+        assert!(oam.time_over);
+        assert_eq!(slivers.len(), 34);
+        assert_eq!(slivers.first().unwrap().sprite.name, 17);
+        assert!(slivers.iter().all(|sliver| sliver.sprite.name != 0));
+    }
+
+    #[test]
+    fn offscreen_slivers_are_not_loaded() {
+        // X = 256 counts toward the range limit, but draws nothing
+        let sprites = [(256, 10), (0x1F8, 10), (248, 10)];
+        let mut oam = oam_with_sprites(&sprites, 0, true);
+
+        let slivers = oam.evaluate_line(12, None);
+
+        assert_eq!(slivers.len(), 2);
+        assert_eq!(slivers[0].sprite.name, 2);
+        assert_eq!(slivers[0].col, 0);
+        assert_eq!(slivers[1].sprite.name, 1);
+        assert_eq!(slivers[1].col, 8);
+    }
+
+    #[test]
+    fn priority_rotation_changes_first_sprite() {
+        let sprites: Vec<_> = (0..40).map(|i| (i * 6, 10)).collect();
+        let mut oam = oam_with_sprites(&sprites, 0, false);
+        oam.oa_addl(10 << 1);
+        oam.oa_addh(0x80);
+
+        let slivers = oam.evaluate_line(12, None);
+
+        // Sprites 10 to 39 and then 0 and 1 after wrapping around
+        assert!(oam.range_over);
+        assert_eq!(slivers.first().unwrap().sprite.name, 1);
+        assert_eq!(slivers.last().unwrap().sprite.name, 10);
+    }
+
+    #[test]
+    fn obj_interlace_halves_sprite_height() {
+        let mut oam = oam_with_sprites(&[(0, 10)], 0, true);
+
+        let slivers = oam.evaluate_line(17, Some(true));
+        assert_eq!(slivers.len(), 2);
+        assert_eq!(slivers[0].row, 15);
+
+        assert!(oam.evaluate_line(18, Some(false)).is_empty());
+    }
+
+    #[test]
+    fn flags_are_sticky_until_cleared() {
+        let sprites: Vec<_> = (0..33).map(|i| (i * 7, 10)).collect();
+        let mut oam = oam_with_sprites(&sprites, 0, false);
+
+        oam.evaluate_line(12, None);
+        oam.evaluate_line(100, None);
+        assert!(oam.range_over);
+
+        oam.clear_flags();
+        assert!(!oam.range_over);
+    }
+
+    #[test]
+    fn high_table_is_mirrored_up_to_the_end_of_the_address_space() {
+        let mut oam = Oam::new();
+        oam.oa_addl(0x10);
+        oam.oa_addh(0x01);
+        oam.oa_addr_write(0xAB, true);
+
+        oam.oa_addl(0x00);
+        oam.oa_addh(0x01);
+        assert_eq!(oam.oa_addr_read(true), 0xAB);
+
+        oam.oa_addl(0xFF);
+        oam.oa_addh(0x01);
+        oam.oa_addr_read(true);
+        oam.oa_addr_read(true);
+        // The address wraps back to the low table after $3FF
+        assert_eq!(oam.internal_addr, 0);
+    }
+
+    #[test]
+    fn vblank_reloads_the_address_after_writes() {
+        let mut oam = Oam::new();
+        oam.oa_addl(0x02);
+        oam.oa_addh(0x00);
+        oam.oa_addr_write(0x11, true);
+        oam.oa_addr_write(0x22, true);
+        oam.oa_addr_write(0x33, false);
+        oam.oa_addr_write(0x44, false);
+        assert_eq!(oam.ram[6..8], [0x00, 0x00]);
+
+        oam.reload_addr();
+        assert_eq!(oam.oa_addr_read(true), 0x11);
+    }
+
+    #[test]
+    fn reads_during_active_display_return_zero_but_increment() {
+        let mut oam = Oam::new();
+        oam.oa_addr_write(0x11, true);
+        oam.oa_addr_write(0x22, true);
+
+        oam.reload_addr();
+        assert_eq!(oam.oa_addr_read(false), 0x00);
+        assert_eq!(oam.oa_addr_read(true), 0x22);
+    }
 }
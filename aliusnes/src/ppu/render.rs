@@ -1,5 +1,9 @@
-use crate::ppu::tile::{BitPlane, Bpp2, Bpp4, Bpp8, TileMapEntry};
-use crate::ppu::{Ppu, WIDTH};
+use std::ops::Range;
+
+use crate::ppu::color::Color;
+use crate::ppu::tile::{BitPlane, Bpp2, Bpp4, Bpp8, OffsetPerTileEntry, TileMapEntry};
+use crate::ppu::window::{COLOR_LAYER, OBJ_LAYER};
+use crate::ppu::{FB_WIDTH, Ppu, WIDTH};
 
 #[derive(Clone, Copy)]
 enum BackgroundId {
@@ -9,6 +13,41 @@ enum BackgroundId {
     BG4,
 }
 
+#[derive(Clone, Copy)]
+struct BgPixel {
+    color: u16,
+    cgram_entry: u8,
+    priority: bool,
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct ObjPixel {
+    color: u16,
+    cgram_entry: u8,
+    priority: u8,
+    palette: u8,
+}
+
+#[derive(Clone, Copy)]
+enum PixelSource {
+    Background(BackgroundId),
+    Object(u8),
+    Backdrop,
+}
+
+#[derive(Clone, Copy)]
+struct ScreenPixel {
+    color: u16,
+    cgram_entry: u8,
+    source: PixelSource,
+}
+
+#[derive(Clone, Copy)]
+enum Screen {
+    Main,
+    Sub,
+}
+
 #[derive(Clone, Copy)]
 enum Layer {
     Background(BackgroundId, bool),
@@ -33,48 +72,195 @@ const H4: Layer = Background(BG4, true);
 
 impl Ppu {
     pub fn render_scanline(&mut self, screen_y: usize) {
-        let fb_line_start = (screen_y - 1) * WIDTH;
-        let fb_line_end = fb_line_start + self.screen_width;
+        self.render_pixels(screen_y, 0..WIDTH);
+    }
+
+    /// Renders the line with the current PPU state but only outputs the
+    /// pixels in `columns`, so the dot renderer can draw it in pieces.
+    /// Sprites are evaluated once, when the first piece is drawn
+    pub(super) fn render_pixels(&mut self, screen_y: usize, columns: Range<usize>) {
+        let fb_row = if self.interlaced_frame {
+            ((screen_y - 1) << 1) | usize::from(self.counters.odd_frame())
+        } else {
+            screen_y - 1
+        };
+        let fb_line_start = fb_row * FB_WIDTH + 2 * columns.start;
+        let fb_line_end = fb_row * FB_WIDTH + 2 * columns.end;
 
-        if self.ini_display.screen_brightness() == 0 || self.ini_display.force_blanking() {
-            self.frame_buffer[fb_line_start..fb_line_end].fill([0; 3]);
+        if columns.start == 0 {
+            let mut obj_data: [Option<ObjPixel>; WIDTH] = [None; WIDTH];
+            if !self.ini_display.force_blanking() {
+                self.draw_sprites(screen_y, &mut obj_data);
+            }
+            self.line_sprites = obj_data;
+        }
+
+        if self.ini_display.force_blanking() {
+            self.render_buffer[fb_line_start..fb_line_end].fill([0; 3]);
             return;
         }
-        let backdrop = self.rgb555_to_rgb888(self.color.cgram[0]);
-        self.frame_buffer[fb_line_start..fb_line_end].fill(backdrop);
 
-        let mut bg_data: [[(u16, bool); WIDTH]; 4] = [
-            [(0, false); WIDTH],
-            [(0, false); WIDTH],
-            [(0, false); WIDTH],
-            [(0, false); WIDTH],
-        ];
+        if self.ini_display.screen_brightness() == 0 {
+            self.render_buffer[fb_line_start..fb_line_end].fill([0; 3]);
+            return;
+        }
+
+        let mut bg_data: [[Option<BgPixel>; FB_WIDTH]; 4] = [[None; FB_WIDTH]; 4];
         let layers = self.decode_bg_mode(screen_y, &mut bg_data).to_vec();
 
-        //todo sprite and sub screen rendering
+        // In hi-res modes backgrounds are drawn 512 pixels wide,
+        // even columns go to the sub screen and odd ones to the main screen
+        let hires_bgs = self.background.bg_mode.is_hires();
+        let hires = hires_bgs || self.set_ini.high_res_mode();
+
+        let main_screen = self.compose_screen(Screen::Main, &layers, &bg_data, columns.clone());
+        let sub_screen = self.compose_screen(Screen::Sub, &layers, &bg_data, columns.clone());
+
+        let color_window = self.window.layer_area(COLOR_LAYER);
+
+        for x in columns {
+            let color = self.color_math(main_screen[x], sub_screen[x], color_window[x]);
+            let main_pixel = self.rgb555_to_rgb888(color);
+            let sub_pixel = if hires {
+                self.rgb555_to_rgb888(sub_screen[x].color)
+            } else {
+                main_pixel
+            };
+            self.fetched_cgram[x] = main_screen[x].cgram_entry;
+            self.render_buffer[fb_row * FB_WIDTH + 2 * x] = sub_pixel;
+            self.render_buffer[fb_row * FB_WIDTH + 2 * x + 1] = main_pixel;
+        }
+        self.hires_frame |= hires;
+    }
+
+    /// Draws the layers enabled on `screen` back to front over the backdrop
+    /// in `columns`, skipping the pixels masked by their window.
+    /// The sub screen backdrop is the fixed color
+    fn compose_screen(
+        &self,
+        screen: Screen,
+        layers: &[Layer],
+        bg_data: &[[Option<BgPixel>; FB_WIDTH]; 4],
+        columns: Range<usize>,
+    ) -> [ScreenPixel; WIDTH] {
+        let (backdrop, column) = match screen {
+            Screen::Main => (self.color.cgram[0], 1),
+            Screen::Sub => (self.color.fixed_color, 0),
+        };
+        let hires_bgs = self.background.bg_mode.is_hires();
+        let window_disable = match screen {
+            Screen::Main => self.window.main_screen_disable,
+            Screen::Sub => self.window.sub_screen_disable,
+        };
+        let masked_area = |layer: usize| {
+            if window_disable & (1 << layer) != 0 {
+                self.window.layer_area(layer)
+            } else {
+                [false; WIDTH]
+            }
+        };
+        let mut line = [ScreenPixel {
+            color: backdrop,
+            cgram_entry: 0,
+            source: PixelSource::Backdrop,
+        }; WIDTH];
 
         for layer in layers.iter().rev() {
             match layer {
                 Background(id, layer_priority) => {
-                    if !self.background.backgrounds[*id as usize].enabled_on_main_screen {
+                    let bg = &self.background.backgrounds[*id as usize];
+                    let enabled = match screen {
+                        Screen::Main => bg.enabled_on_main_screen,
+                        Screen::Sub => bg.enabled_on_sub_screen,
+                    };
+                    if !enabled {
                         continue;
                     }
-                    for (x, (pixel, priority)) in bg_data[*id as usize].iter().enumerate() {
-                        if layer_priority != priority {
-                            continue;
+                    let masked = masked_area(*id as usize);
+                    for (x, pixel) in columns.clone().zip(&mut line[columns.clone()]) {
+                        let bg_x = if hires_bgs { 2 * x + column } else { x };
+                        match bg_data[*id as usize][bg_x] {
+                            Some(bg_pixel)
+                                if bg_pixel.priority == *layer_priority && !masked[x] =>
+                            {
+                                *pixel = ScreenPixel {
+                                    color: bg_pixel.color,
+                                    cgram_entry: bg_pixel.cgram_entry,
+                                    source: PixelSource::Background(*id),
+                                };
+                            },
+                            _ => {},
                         }
-                        if *pixel > 0 {
-                            self.frame_buffer[fb_line_start..fb_line_end][x] =
-                                self.rgb555_to_rgb888(*pixel);
+                    }
+                },
+                Object(layer_priority) => {
+                    let enabled = match screen {
+                        Screen::Main => self.oam.enabled_on_main_screen,
+                        Screen::Sub => self.oam.enabled_on_sub_screen,
+                    };
+                    if !enabled {
+                        continue;
+                    }
+                    let masked = masked_area(OBJ_LAYER);
+                    for (x, pixel) in columns.clone().zip(&mut line[columns.clone()]) {
+                        match self.line_sprites[x] {
+                            Some(obj) if obj.priority == *layer_priority && !masked[x] => {
+                                *pixel = ScreenPixel {
+                                    color: obj.color,
+                                    cgram_entry: obj.cgram_entry,
+                                    source: PixelSource::Object(obj.palette),
+                                };
+                            },
+                            _ => {},
                         }
                     }
                 },
-                Object(_layer_priority) => {},
             }
         }
+        line
     }
 
-    fn decode_bg_mode(&self, screen_y: usize, bg_data: &mut [[(u16, bool); WIDTH]; 4]) -> &[Layer] {
+    /// Combines a main screen pixel with the sub screen or the fixed color
+    fn color_math(&self, main: ScreenPixel, sub: ScreenPixel, in_color_window: bool) -> u16 {
+        let cgwsel = &self.color.cgwsel;
+        let cgadsub = &self.color.cgadsub;
+
+        let region_applies = |region: u8| match region {
+            0 => false,
+            1 => !in_color_window,
+            2 => in_color_window,
+            _ => true,
+        };
+        let clip_to_black = region_applies(cgwsel.main_screen_black_region());
+        let prevent_math = region_applies(cgwsel.sub_screen_transparent_region());
+
+        let color = if clip_to_black { 0 } else { main.color };
+        let math_enabled = match main.source {
+            PixelSource::Background(id) => cgadsub.bg_color_math_enabled(id as usize),
+            // Only sprites using palettes 4 to 7 take part in color math
+            PixelSource::Object(palette) => cgadsub.obj_color_math_enabled() && palette >= 4,
+            PixelSource::Backdrop => cgadsub.backdrop_color_math_enabled(),
+        };
+        if prevent_math || !math_enabled {
+            return color;
+        }
+
+        let (addend, sub_transparent) = match sub.source {
+            PixelSource::Backdrop => (self.color.fixed_color, true),
+            _ if cgwsel.addend_is_sub_screen() => (sub.color, false),
+            _ => (self.color.fixed_color, false),
+        };
+        let halve = cgadsub.halve_color_math_result()
+            && !clip_to_black
+            && !(sub_transparent && cgwsel.addend_is_sub_screen());
+        self.color.blend(color, addend, halve)
+    }
+
+    fn decode_bg_mode(
+        &self,
+        screen_y: usize,
+        bg_data: &mut [[Option<BgPixel>; FB_WIDTH]; 4],
+    ) -> &[Layer] {
         match self.background.bg_mode.bg_mode() {
             0 => {
                 self.draw_background::<Bpp2, 0>(screen_y, BG1, &mut (bg_data)[BG1 as usize]);
@@ -108,11 +294,25 @@ impl Ppu {
                 self.draw_background::<Bpp2, 4>(screen_y, BG2, &mut (*bg_data)[BG2 as usize]);
                 &[S3, H1, S2, H2, S1, L1, S0, L2]
             },
+            5 => {
+                self.draw_background::<Bpp4, 5>(screen_y, BG1, &mut (*bg_data)[BG1 as usize]);
+                self.draw_background::<Bpp2, 5>(screen_y, BG2, &mut (*bg_data)[BG2 as usize]);
+                &[S3, H1, S2, H2, S1, L1, S0, L2]
+            },
             6 => {
                 self.draw_background::<Bpp4, 6>(screen_y, BG1, &mut (*bg_data)[BG1 as usize]);
                 &[S3, H1, S2, S1, L1, S0]
             },
-            _ => unimplemented!("mode {}", self.background.bg_mode.bg_mode()),
+            7 => {
+                self.draw_mode7_background(screen_y, BG1, &mut (*bg_data)[BG1 as usize]);
+                if self.set_ini.extbg_mode() {
+                    self.draw_mode7_background(screen_y, BG2, &mut (*bg_data)[BG2 as usize]);
+                    &[S3, S2, H2, S1, L1, S0, L2]
+                } else {
+                    &[S3, S2, S1, L1, S0]
+                }
+            },
+            _ => unreachable!(),
         }
     }
 
@@ -120,39 +320,838 @@ impl Ppu {
         &self,
         screen_y: usize,
         bg_idx: BackgroundId,
-        data: &mut [(u16, bool); 256],
+        data: &mut [Option<BgPixel>; FB_WIDTH],
     ) where
         [(); BPP::PLANES]:,
     {
         let bg = self.background.backgrounds[bg_idx as usize];
-        if !bg.enabled_on_main_screen {
+        if !bg.is_enabled() {
             return;
         }
 
-        let y = screen_y + bg.bg_vofs as usize;
-        for (screen_x, pixel_data) in data.iter_mut().enumerate().take(self.screen_width) {
-            let x = screen_x + bg.bg_hofs as usize;
+        // Hi-res modes use 16 pixels wide tiles over a 512 pixels wide line,
+        // 16x16 tiles are made of the characters N, N+1, N+16 and N+17
+        let large_tiles = self.background.bg_mode.large_tiles(bg_idx as usize);
+        let width = if BG_MODE == 5 || BG_MODE == 6 {
+            FB_WIDTH
+        } else {
+            WIDTH
+        };
+        let tile_width = if width == FB_WIDTH || large_tiles {
+            16
+        } else {
+            8
+        };
+        let tile_height = if large_tiles { 16 } else { 8 };
+        let hires_shift = usize::from(width == FB_WIDTH);
+        let offset_per_tile = matches!(BG_MODE, 2 | 4 | 6) && matches!(bg_idx, BG1 | BG2);
+
+        // Interlaced hi-res modes draw the lines of both fields
+        let screen_y = self.background.bg_line(bg_idx as usize, screen_y);
+        let line = if width == FB_WIDTH && self.set_ini.screen_interlacing() {
+            (screen_y << 1) | usize::from(self.counters.odd_frame())
+        } else {
+            screen_y
+        };
+        let mosaic_block = self.background.mosaic_block(bg_idx as usize) << hires_shift;
 
-            let tile_map_addr = bg.tile_map_addr(x / 8, y / 8);
+        for (screen_x, pixel_data) in data.iter_mut().enumerate().take(width) {
+            if screen_x % mosaic_block != 0 {
+                continue;
+            }
+            let (h_scroll, v_scroll) = if offset_per_tile {
+                self.offset_per_tile::<BG_MODE>(bg_idx, screen_x >> hires_shift)
+            } else {
+                (bg.bg_hofs, bg.bg_vofs)
+            };
+            let x = screen_x + ((h_scroll as usize) << hires_shift);
+            let y = line + v_scroll as usize;
+
+            let tile_map_addr = bg.tile_map_addr(x / tile_width, y / tile_height);
             let tile = TileMapEntry(self.vram[tile_map_addr]);
 
-            let (col, row) = tile.adjust_coords_to_flipping(x, y);
+            let (col, row) = tile.adjust_coords_to_flipping(
+                x % tile_width,
+                y % tile_height,
+                tile_width,
+                tile_height,
+            );
+            let character =
+                (tile.tile_index() + (col / 8) as u16 + ((row / 8) as u16) * 16) & 0x3FF;
 
-            let tile_addr = bg.tileset_addr + tile.tile_index() * BPP::WORDS_PER_ROW * 8;
-            let planes = self.vram.planes(tile_addr as usize + row);
+            let tile_addr = bg.tileset_addr + character * BPP::WORDS_PER_ROW * 8;
+            let planes = self.vram.planes(tile_addr as usize + (row % 8));
 
-            let raw_pixel = BPP::pixel(planes, col);
-            let pixel = if raw_pixel == 0 {
-                0
-            } else {
-                self.color.pixel_color::<BPP, BG_MODE>(
-                    bg_idx as u8,
-                    tile.palette_selection(),
-                    raw_pixel,
-                )
+            let raw_pixel = BPP::pixel(planes, 7 - (col % 8));
+            let palette = tile.palette_selection();
+            *pixel_data = (raw_pixel != 0).then(|| BgPixel {
+                color: self
+                    .color
+                    .pixel_color::<BPP, BG_MODE>(bg_idx as u8, palette, raw_pixel),
+                cgram_entry: Color::bg_entry::<BPP, BG_MODE>(bg_idx as u8, palette, raw_pixel),
+                priority: tile.priority(),
+            });
+        }
+        apply_horizontal_mosaic(&mut data[..width], mosaic_block);
+    }
+}
+
+impl Ppu {
+    /// In offset-per-tile modes the BG3 tilemap holds the scroll values of
+    /// BG1 and BG2 for every 8 pixels column, except the leftmost one.
+    /// Mode 4 fetches a single entry that applies either horizontally or
+    /// vertically, the other modes fetch both from two consecutive rows
+    fn offset_per_tile<const BG_MODE: u8>(
+        &self,
+        bg_idx: BackgroundId,
+        screen_x: usize,
+    ) -> (u16, u16) {
+        let bg = self.background.backgrounds[bg_idx as usize];
+        let (mut h_scroll, mut v_scroll) = (bg.bg_hofs, bg.bg_vofs);
+
+        let column = (screen_x + (h_scroll as usize & 7)) / 8;
+        if column == 0 {
+            return (h_scroll, v_scroll);
+        }
+
+        let bg3 = self.background.backgrounds[BG3 as usize];
+        let tile_x = (column - 1) + (bg3.bg_hofs as usize >> 3);
+        let tile_y = bg3.bg_vofs as usize >> 3;
+        let h_entry = OffsetPerTileEntry(self.vram[bg3.tile_map_addr(tile_x, tile_y)]);
+
+        if BG_MODE == 4 {
+            if h_entry.applies_to(bg_idx as usize) {
+                if h_entry.vertical() {
+                    v_scroll = h_entry.offset();
+                } else {
+                    h_scroll = (h_entry.offset() & !7) | (h_scroll & 7);
+                }
+            }
+        } else {
+            let v_entry = OffsetPerTileEntry(self.vram[bg3.tile_map_addr(tile_x, tile_y + 1)]);
+            if h_entry.applies_to(bg_idx as usize) {
+                h_scroll = (h_entry.offset() & !7) | (h_scroll & 7);
+            }
+            if v_entry.applies_to(bg_idx as usize) {
+                v_scroll = v_entry.offset();
+            }
+        }
+        (h_scroll, v_scroll)
+    }
+}
+
+/// Repeats the first pixel of each block across the whole block
+fn apply_horizontal_mosaic(data: &mut [Option<BgPixel>], block: usize) {
+    if block == 1 {
+        return;
+    }
+    for x in 0..data.len() {
+        data[x] = data[x - x % block];
+    }
+}
+
+impl Ppu {
+    /// BG2 is only drawn in EXTBG mode, where it reuses the BG1 pixels
+    /// taking bit 7 as priority
+    fn draw_mode7_background(
+        &self,
+        screen_y: usize,
+        bg_idx: BackgroundId,
+        data: &mut [Option<BgPixel>; FB_WIDTH],
+    ) {
+        if !self.background.backgrounds[bg_idx as usize].is_enabled() {
+            return;
+        }
+
+        let screen_y = self.background.bg_line(bg_idx as usize, screen_y);
+        let mosaic_block = self.background.mosaic_block(bg_idx as usize);
+
+        let origin = self.mode7.scanline_origin(screen_y);
+        for (screen_x, pixel_data) in data.iter_mut().enumerate().take(WIDTH) {
+            if screen_x % mosaic_block != 0 {
+                continue;
+            }
+            let (x, y) = self.mode7.pixel_coords(origin, screen_x);
+            let outside_playfield = (x | y) & !0x3FF != 0;
+
+            let tile = match self.mode7.m7sel.screen_over() {
+                2 if outside_playfield => continue,
+                3 if outside_playfield => 0,
+                _ => self
+                    .vram
+                    .mode7_tile(((x >> 3) & 0x7F) as usize, ((y >> 3) & 0x7F) as usize),
             };
+            let mut raw_pixel = self
+                .vram
+                .mode7_pixel(tile, (x & 7) as usize, (y & 7) as usize);
+            let mut priority = false;
+            if matches!(bg_idx, BG2) {
+                priority = raw_pixel & 0x80 != 0;
+                raw_pixel &= 0x7F;
+            }
 
-            *pixel_data = (pixel, tile.priority());
+            *pixel_data = (raw_pixel != 0).then(|| BgPixel {
+                color: self
+                    .color
+                    .pixel_color::<Bpp8, 7>(bg_idx as u8, 0, raw_pixel),
+                cgram_entry: raw_pixel,
+                priority,
+            });
         }
+        apply_horizontal_mosaic(&mut data[..WIDTH], mosaic_block);
+    }
+
+    fn draw_sprites(&mut self, screen_y: usize, data: &mut [Option<ObjPixel>; WIDTH]) {
+        let interlace_field = self
+            .set_ini
+            .obj_interlacing()
+            .then(|| self.counters.odd_frame());
+        let slivers = self
+            .oam
+            .evaluate_line((screen_y - 1) as u8, interlace_field);
+        if !self.oam.enabled_on_main_screen && !self.oam.enabled_on_sub_screen {
+            return;
+        }
+        let objsel = self.oam.objsel;
+
+        // Slivers are loaded starting from the lowest priority sprite,
+        // so each one is drawn over the previous ones
+        for sliver in slivers {
+            let sprite = sliver.sprite;
+            let row = sliver.row;
+
+            for sprite_x in sliver.col..sliver.col + 8 {
+                let screen_x = sprite.screen_x() + sprite_x as i16;
+                if !(0..WIDTH as i16).contains(&screen_x) {
+                    continue;
+                }
+
+                let col = if sprite.attributes.flip_horizontal() {
+                    sliver.width - 1 - sprite_x
+                } else {
+                    sprite_x
+                };
+                let name = (sprite.name & 0x100)
+                    | ((sprite.name + (col >> 3)) & 0x0F)
+                    | (((sprite.name & 0xF0) + ((row >> 3) << 4)) & 0xF0);
+                let tile_addr = objsel.tile_addr(name) + (row & 7);
+                let planes = self.vram.planes::<Bpp4>(tile_addr as usize);
+
+                let raw_pixel = Bpp4::pixel(planes, 7 - (col as usize & 7));
+                if raw_pixel == 0 {
+                    continue;
+                }
+
+                let palette = sprite.attributes.palette();
+                data[screen_x as usize] = Some(ObjPixel {
+                    color: self.color.obj_color(palette, raw_pixel),
+                    cgram_entry: Color::obj_entry(palette, raw_pixel),
+                    priority: sprite.attributes.priority(),
+                    palette,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Access;
+    use crate::cart::info::Model;
+
+    /// Mode 1 BG1 with 16x16 tiles on a 64x64 tilemap at $0400, where
+    /// characters 1, 2, 17 and 18 use the colors 1 to 4
+    fn ppu_with_large_tiles() -> Ppu {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.access_restrictions = false;
+        ppu.write(0x2100, 0x0F);
+        ppu.write(0x2105, 0x11);
+        ppu.write(0x2107, 0x07);
+        ppu.write(0x212C, 0x01);
+        for (index, color) in [0x001F, 0x03E0, 0x7C00, 0x7FFF].into_iter().enumerate() {
+            ppu.write(0x2121, index as u8 + 1);
+            ppu.write(0x2122, color as u8);
+            ppu.write(0x2122, (color >> 8) as u8);
+        }
+        // The planes 2 and 3 of a 4bpp character follow its first 8 words
+        for (character, offset, planes) in [
+            (1, 0, 0x00FF),
+            (2, 0, 0xFF00),
+            (17, 0, 0xFFFF),
+            (18, 8, 0x00FF),
+        ] {
+            for row in 0..8 {
+                ppu.vram[character * 16 + offset + row] = planes;
+            }
+        }
+        ppu
+    }
+
+    fn line(ppu: &Ppu, y: usize) -> Vec<[u8; 3]> {
+        ppu.render_buffer[(y - 1) * FB_WIDTH..y * FB_WIDTH]
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .copied()
+            .collect()
+    }
+
+    /// Mode 7 BG1 with the identity matrix, where the pixels of tile 1
+    /// use the colors 1 to 8 from left to right
+    fn ppu_in_mode_7() -> Ppu {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.access_restrictions = false;
+        ppu.write(0x2100, 0x0F);
+        ppu.write(0x2105, 0x07);
+        ppu.write(0x212C, 0x01);
+        for matrix in [0x211B, 0x211E] {
+            ppu.write(matrix, 0x00);
+            ppu.write(matrix, 0x01);
+        }
+        for index in 0..16 {
+            ppu.color.cgram[index] = 0x0421 * index as u16;
+        }
+        for pixel in 0..64 {
+            set_mode7_pixel(&mut ppu, 1, pixel, (pixel % 8) as u8 + 1);
+        }
+        ppu
+    }
+
+    fn set_mode7_tile(ppu: &mut Ppu, tile_x: usize, tile_y: usize, tile: u8) {
+        let entry = &mut ppu.vram[(tile_y << 7) | tile_x];
+        *entry = (*entry & 0xFF00) | u16::from(tile);
+    }
+
+    fn set_mode7_pixel(ppu: &mut Ppu, tile: usize, pixel: usize, index: u8) {
+        let entry = &mut ppu.vram[(tile << 6) | pixel];
+        *entry = (*entry & 0x00FF) | (u16::from(index) << 8);
+    }
+
+    fn cgram_color(ppu: &Ppu, index: usize) -> [u8; 3] {
+        ppu.rgb555_to_rgb888(ppu.color.cgram[index])
+    }
+
+    #[test]
+    fn large_tiles_combine_four_characters() {
+        let mut ppu = ppu_with_large_tiles();
+        ppu.vram[0x400] = 0x0001;
+
+        ppu.render_scanline(1);
+        let top = line(&ppu, 1);
+        assert_eq!(top[0], [255, 0, 0]);
+        assert_eq!(top[8], [0, 255, 0]);
+        assert_eq!(top[16], [0, 0, 0]);
+
+        ppu.render_scanline(9);
+        let bottom = line(&ppu, 9);
+        assert_eq!(bottom[0], [0, 0, 255]);
+        assert_eq!(bottom[8], [255, 255, 255]);
+    }
+
+    #[test]
+    fn large_tiles_flip_the_whole_block() {
+        let mut ppu = ppu_with_large_tiles();
+        ppu.vram[0x400] = 0xC001;
+
+        ppu.render_scanline(1);
+        let top = line(&ppu, 1);
+        assert_eq!(top[0], [255, 255, 255]);
+        assert_eq!(top[8], [0, 0, 255]);
+
+        ppu.render_scanline(9);
+        let bottom = line(&ppu, 9);
+        assert_eq!(bottom[0], [0, 255, 0]);
+        assert_eq!(bottom[8], [255, 0, 0]);
+    }
+
+    #[test]
+    fn large_tiles_reach_the_fourth_screen() {
+        let mut ppu = ppu_with_large_tiles();
+        // 16x16 tiles make the 64x64 tilemap 1024 pixels wide
+        ppu.vram[0x1000] = 0x0001;
+        ppu.write(0x210D, 0x00);
+        ppu.write(0x210D, 0x02);
+        ppu.write(0x210E, 0xFF);
+        ppu.write(0x210E, 0x01);
+
+        ppu.render_scanline(1);
+        assert_eq!(line(&ppu, 1)[0], [255, 0, 0]);
+    }
+
+    #[test]
+    fn brightness_scales_the_composited_pixel() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.color.cgram[0] = 0x7FFF;
+
+        // A fade-in raising the brightness one step per line
+        for (line_y, brightness, level) in [(1, 0x01, 17), (2, 0x07, 119), (3, 0x0F, 255)] {
+            ppu.write(0x2100, brightness);
+            ppu.render_scanline(line_y);
+            assert_eq!(line(&ppu, line_y)[0], [level; 3]);
+        }
+
+        ppu.write(0x2100, 0x00);
+        ppu.render_scanline(4);
+        assert_eq!(line(&ppu, 4)[0], [0; 3]);
+    }
+
+    #[test]
+    fn force_blank_outputs_black_and_keeps_vram_accessible() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.color.cgram[0] = 0x7FFF;
+        ppu.write(0x2100, 0x8F);
+
+        ppu.write(0x2115, 0x80);
+        ppu.write(0x2116, 0x00);
+        ppu.write(0x2117, 0x00);
+        ppu.write(0x2118, 0x34);
+        ppu.write(0x2119, 0x12);
+        assert_eq!(ppu.vram[0], 0x1234);
+
+        ppu.render_scanline(1);
+        assert_eq!(line(&ppu, 1)[0], [0; 3]);
+    }
+
+    #[test]
+    fn dot_renderer_splits_the_line_at_register_writes() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.dot_renderer = true;
+        ppu.write(0x2100, 0x0F);
+        ppu.counters.vertical_counter = 1;
+        ppu.color.cgram[0] = 0x001F;
+
+        // Dot 22 is the first visible pixel, each dot lasts 4 master cycles
+        ppu.catch_up(4 * (22 + 100));
+        ppu.color.cgram[0] = 0x03E0;
+        ppu.catch_up(4 * (22 + 200));
+        ppu.color.cgram[0] = 0x7C00;
+        ppu.draw_pixels_until(WIDTH);
+
+        let line = line(&ppu, 1);
+        assert_eq!(line[99], [255, 0, 0]);
+        assert_eq!(line[100], [0, 255, 0]);
+        assert_eq!(line[199], [0, 255, 0]);
+        assert_eq!(line[200], [0, 0, 255]);
+        assert_eq!(line[255], [0, 0, 255]);
+    }
+
+    #[test]
+    fn dot_renderer_counts_dots_from_the_line_start() {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.dot_renderer = true;
+        ppu.write(0x2100, 0x0F);
+        ppu.counters.vertical_counter = 1;
+        ppu.color.cgram[0] = 0x001F;
+        // A short 1360 cycles line moved the start off the 1364 cycles grid
+        ppu.line_start = 5 * 1364 + 1360;
+
+        ppu.catch_up(ppu.line_start + 4 * (22 + 100));
+        ppu.color.cgram[0] = 0x03E0;
+        ppu.draw_pixels_until(WIDTH);
+
+        let line = line(&ppu, 1);
+        assert_eq!(line[99], [255, 0, 0]);
+        assert_eq!(line[100], [0, 255, 0]);
+    }
+
+    #[test]
+    fn mode_7_identity_matrix_maps_the_screen_on_the_playfield() {
+        let mut ppu = ppu_in_mode_7();
+        set_mode7_tile(&mut ppu, 0, 0, 1);
+
+        ppu.render_scanline(1);
+        let line = line(&ppu, 1);
+        for (x, pixel) in line.iter().take(8).enumerate() {
+            assert_eq!(*pixel, cgram_color(&ppu, x + 1));
+        }
+        assert_eq!(line[8], cgram_color(&ppu, 0));
+    }
+
+    #[test]
+    fn mode_7_flips_mirror_the_screen() {
+        let mut ppu = ppu_in_mode_7();
+        set_mode7_tile(&mut ppu, 0, 0, 1);
+
+        // Pixel 255 samples the playfield column 0
+        ppu.write(0x211A, 0x01);
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[255], cgram_color(&ppu, 1));
+        assert_eq!(line_1[248], cgram_color(&ppu, 8));
+        assert_eq!(line_1[0], cgram_color(&ppu, 0));
+
+        // Line 1 samples the playfield row 254
+        set_mode7_tile(&mut ppu, 0, 0, 0);
+        set_mode7_tile(&mut ppu, 0, 31, 1);
+        ppu.write(0x211A, 0x02);
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[0], cgram_color(&ppu, 1));
+        assert_eq!(line_1[7], cgram_color(&ppu, 8));
+    }
+
+    #[test]
+    fn mode_7_screen_over_handles_pixels_outside_the_playfield() {
+        let mut ppu = ppu_in_mode_7();
+        set_mode7_tile(&mut ppu, 0, 0, 1);
+        set_mode7_tile(&mut ppu, 127, 0, 1);
+        for pixel in 0..64 {
+            set_mode7_pixel(&mut ppu, 0, pixel, 9);
+        }
+        // Scrolling 8 pixels left puts the columns 0 to 7 at X = -8 to -1
+        ppu.write(0x210D, 0xF8);
+        ppu.write(0x210D, 0x1F);
+
+        // The playfield wraps around, column -8 is the tile 127
+        ppu.write(0x211A, 0x00);
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[0], cgram_color(&ppu, 1));
+        assert_eq!(line_1[8], cgram_color(&ppu, 1));
+        assert_eq!(line_1[15], cgram_color(&ppu, 8));
+
+        // Outside pixels are transparent
+        ppu.write(0x211A, 0x80);
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[0], cgram_color(&ppu, 0));
+        assert_eq!(line_1[7], cgram_color(&ppu, 0));
+        assert_eq!(line_1[8], cgram_color(&ppu, 1));
+
+        // Outside pixels use the tile 0
+        ppu.write(0x211A, 0xC0);
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[0], cgram_color(&ppu, 9));
+        assert_eq!(line_1[7], cgram_color(&ppu, 9));
+        assert_eq!(line_1[8], cgram_color(&ppu, 1));
+    }
+
+    #[test]
+    fn extbg_splits_bg2_pixels_into_priority_and_color() {
+        let mut ppu = ppu_in_mode_7();
+        set_mode7_tile(&mut ppu, 0, 0, 1);
+        set_mode7_pixel(&mut ppu, 1, 8, 0x85);
+        set_mode7_pixel(&mut ppu, 1, 9, 0x05);
+        ppu.write(0x2133, 0x40);
+
+        // The low 7 bits select the color of BG2
+        ppu.write(0x212C, 0x02);
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[0], cgram_color(&ppu, 5));
+        assert_eq!(line_1[1], cgram_color(&ppu, 5));
+
+        // Bit 7 puts BG2 in front of BG1, which uses direct color here
+        ppu.write(0x212C, 0x03);
+        ppu.write(0x2130, 0x01);
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[0], cgram_color(&ppu, 5));
+        assert_eq!(line_1[1], ppu.rgb555_to_rgb888(0x0014));
+    }
+
+    #[test]
+    fn hires_interleaves_the_sub_and_main_screens() {
+        let mut ppu = ppu_with_large_tiles();
+        ppu.vram[0x400] = 0x0001;
+        ppu.write(0x2132, 0x9F);
+        ppu.write(0x2133, 0x08);
+
+        // The sub screen only shows the fixed color
+        ppu.render_scanline(1);
+        let fb_line = &ppu.render_buffer[..FB_WIDTH];
+        assert_eq!(fb_line[0], [0, 0, 255]);
+        assert_eq!(fb_line[1], [255, 0, 0]);
+        assert_eq!(fb_line[16], [0, 0, 255]);
+        assert_eq!(fb_line[17], [0, 255, 0]);
+    }
+
+    #[test]
+    fn hires_modes_use_16_pixels_wide_tiles() {
+        for mode in [0x05, 0x06] {
+            let mut ppu = ppu_with_large_tiles();
+            ppu.vram[0x400] = 0x0001;
+            ppu.write(0x2105, mode);
+            ppu.write(0x212D, 0x01);
+
+            // Characters 1 and 2 cover 8 pixels each of the 512 pixels line
+            ppu.render_scanline(1);
+            let fb_line = &ppu.render_buffer[..FB_WIDTH];
+            assert!(fb_line[..8].iter().all(|&pixel| pixel == [255, 0, 0]));
+            assert!(fb_line[8..16].iter().all(|&pixel| pixel == [0, 255, 0]));
+            assert_eq!(fb_line[16], [0, 0, 0]);
+        }
+    }
+
+    fn bg1_pixel(color: u16) -> ScreenPixel {
+        ScreenPixel {
+            color,
+            cgram_entry: 0,
+            source: PixelSource::Background(BG1),
+        }
+    }
+
+    /// BG1 on the main screen, BG2 or the fixed color on the sub screen
+    fn color_math_ppu(cgwsel: u8, cgadsub: u8) -> Ppu {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.write(0x2130, cgwsel);
+        ppu.write(0x2131, cgadsub);
+        // Fixed color with blue 4
+        ppu.write(0x2132, 0x84);
+        ppu
+    }
+
+    #[test]
+    fn color_math_adds_or_subtracts_the_sub_screen() {
+        let sub = ScreenPixel {
+            color: 0x0108,
+            cgram_entry: 0,
+            source: PixelSource::Background(BG2),
+        };
+
+        let ppu = color_math_ppu(0x02, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0118);
+        let ppu = color_math_ppu(0x02, 0x81);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0210), sub, false), 0x0108);
+
+        // The fixed color is the addend when the sub screen is not selected
+        let ppu = color_math_ppu(0x00, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x1010);
+
+        // Layers without color math keep their color
+        let ppu = color_math_ppu(0x02, 0x02);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0010);
+    }
+
+    #[test]
+    fn color_math_clamps_each_channel() {
+        let sub = ScreenPixel {
+            color: 0x0C21,
+            cgram_entry: 0,
+            source: PixelSource::Background(BG2),
+        };
+
+        let ppu = color_math_ppu(0x02, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x03FF), sub, false), 0x0FFF);
+        let ppu = color_math_ppu(0x02, 0x81);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0402), sub, false), 0x0001);
+    }
+
+    #[test]
+    fn half_color_math_skips_the_sub_screen_backdrop() {
+        let sub = ScreenPixel {
+            color: 0x0008,
+            cgram_entry: 0,
+            source: PixelSource::Background(BG2),
+        };
+        let backdrop = ScreenPixel {
+            color: 0x1000,
+            cgram_entry: 0,
+            source: PixelSource::Backdrop,
+        };
+
+        let ppu = color_math_ppu(0x02, 0x41);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x000C);
+        // A transparent sub screen adds the fixed color at full strength
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), backdrop, false), 0x1010);
+
+        // The fixed color addend is halved
+        let ppu = color_math_ppu(0x00, 0x41);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0808);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), backdrop, false), 0x0808);
+    }
+
+    #[test]
+    fn color_window_regions_clip_and_prevent_color_math() {
+        let sub = ScreenPixel {
+            color: 0x0008,
+            cgram_entry: 0,
+            source: PixelSource::Background(BG2),
+        };
+
+        // Clipped pixels are black before the math, which is not halved
+        let ppu = color_math_ppu(0xC2, 0x41);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0008);
+        let ppu = color_math_ppu(0x42, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0008);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, true), 0x0018);
+        let ppu = color_math_ppu(0x82, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0018);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, true), 0x0008);
+
+        // Prevented pixels keep the main screen color
+        let ppu = color_math_ppu(0x32, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0010);
+        let ppu = color_math_ppu(0x12, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0010);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, true), 0x0018);
+        let ppu = color_math_ppu(0x22, 0x01);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, false), 0x0018);
+        assert_eq!(ppu.color_math(bg1_pixel(0x0010), sub, true), 0x0010);
+    }
+
+    #[test]
+    fn window_masks_only_the_screens_selected_by_tmw_and_tsw() {
+        let mut ppu = ppu_with_large_tiles();
+        ppu.vram[0x400] = 0x0001;
+        // BG1 on both screens, in pseudo hi-res to see the sub screen
+        ppu.write(0x212D, 0x01);
+        ppu.write(0x2133, 0x08);
+        ppu.write(0x2123, 0x02);
+        ppu.write(0x2126, 4);
+        ppu.write(0x2127, 11);
+
+        // The even columns hold the sub screen and the odd ones the main screen
+        ppu.write(0x212E, 0x01);
+        ppu.render_scanline(1);
+        let fb_line = &ppu.render_buffer[..FB_WIDTH];
+        assert_eq!(fb_line[2 * 3 + 1], [255, 0, 0]);
+        assert_eq!(fb_line[2 * 4 + 1], [0, 0, 0]);
+        assert_eq!(fb_line[2 * 4], [255, 0, 0]);
+        assert_eq!(fb_line[2 * 12 + 1], [0, 255, 0]);
+
+        ppu.write(0x212E, 0x00);
+        ppu.write(0x212F, 0x01);
+        ppu.render_scanline(1);
+        let fb_line = &ppu.render_buffer[..FB_WIDTH];
+        assert_eq!(fb_line[2 * 4 + 1], [255, 0, 0]);
+        assert_eq!(fb_line[2 * 4], [0, 0, 0]);
+        assert_eq!(fb_line[2 * 3], [255, 0, 0]);
+    }
+
+    #[test]
+    fn mosaic_repeats_the_first_pixel_of_each_block() {
+        let mut ppu = ppu_with_large_tiles();
+        ppu.vram[0x400] = 0x0001;
+        ppu.write(0x2106, 0x51);
+        ppu.background.update_mosaic_line(1);
+
+        // Blocks of 6 pixels start at 0, 6 and 12
+        ppu.render_scanline(1);
+        let line_1 = line(&ppu, 1);
+        assert_eq!(line_1[8], [255, 0, 0]);
+        assert_eq!(line_1[11], [255, 0, 0]);
+        assert_eq!(line_1[12], [0, 255, 0]);
+        assert_eq!(line_1[17], [0, 255, 0]);
+        assert_eq!(line_1[18], [0, 0, 0]);
+    }
+
+    /// BG3 tilemap at $0400 with BG1 and BG2 scrolled 3 pixels right,
+    /// so the first column of offsets applies from pixel 5
+    fn ppu_with_offset_table() -> Ppu {
+        let mut ppu = Ppu::new(Model::Ntsc);
+        ppu.write(0x2109, 0x04);
+        for hofs in [0x210D, 0x210F] {
+            ppu.write(hofs, 0x03);
+            ppu.write(hofs, 0x00);
+        }
+        ppu
+    }
+
+    #[test]
+    fn offset_per_tile_skips_the_first_column() {
+        let mut ppu = ppu_with_offset_table();
+        ppu.vram[0x400] = 0x6100;
+        ppu.vram[0x420] = 0x6050;
+
+        assert_eq!(ppu.offset_per_tile::<2>(BG1, 4), (0x003, 0x000));
+        assert_eq!(ppu.offset_per_tile::<2>(BG1, 5), (0x103, 0x050));
+        assert_eq!(ppu.offset_per_tile::<2>(BG1, 12), (0x103, 0x050));
+        assert_eq!(ppu.offset_per_tile::<2>(BG1, 13), (0x003, 0x000));
+    }
+
+    #[test]
+    fn offset_per_tile_follows_the_bg3_scroll() {
+        let mut ppu = ppu_with_offset_table();
+        // Scrolling BG3 by 2 columns and 1 row
+        ppu.write(0x2111, 0x10);
+        ppu.write(0x2111, 0x00);
+        ppu.write(0x2112, 0x08);
+        ppu.write(0x2112, 0x00);
+        ppu.vram[0x422] = 0x6100;
+        ppu.vram[0x442] = 0x6050;
+
+        assert_eq!(ppu.offset_per_tile::<2>(BG1, 5), (0x103, 0x050));
+        assert_eq!(ppu.offset_per_tile::<6>(BG1, 5), (0x103, 0x050));
+        // Mode 4 only reads the first row
+        assert_eq!(ppu.offset_per_tile::<4>(BG1, 5), (0x103, 0x000));
+    }
+
+    #[test]
+    fn mode_4_offsets_apply_horizontally_or_vertically() {
+        let mut ppu = ppu_with_offset_table();
+        ppu.vram[0x420] = 0x6050;
+
+        ppu.vram[0x400] = 0x6100;
+        assert_eq!(ppu.offset_per_tile::<4>(BG1, 5), (0x103, 0x000));
+        ppu.vram[0x400] = 0xE100;
+        assert_eq!(ppu.offset_per_tile::<4>(BG1, 5), (0x003, 0x100));
+    }
+
+    #[test]
+    fn offset_per_tile_entries_select_the_backgrounds() {
+        let mut ppu = ppu_with_offset_table();
+
+        ppu.vram[0x400] = 0x2100;
+        ppu.vram[0x420] = 0x4050;
+        assert_eq!(ppu.offset_per_tile::<2>(BG1, 5), (0x103, 0x000));
+        assert_eq!(ppu.offset_per_tile::<2>(BG2, 5), (0x003, 0x050));
+
+        ppu.vram[0x400] = 0xC100;
+        assert_eq!(ppu.offset_per_tile::<4>(BG1, 5), (0x003, 0x000));
+        assert_eq!(ppu.offset_per_tile::<4>(BG2, 5), (0x003, 0x100));
+    }
+
+    #[test]
+    fn cgram_writes_while_drawing_land_on_the_fetched_entry() {
+        let mut ppu = ppu_with_large_tiles();
+        ppu.access_restrictions = true;
+        ppu.vram[0x400] = 0x0001;
+        ppu.counters.vertical_counter = 1;
+        ppu.render_scanline(1);
+
+        // Columns 8 to 15 output the color 2 while the color 5 is addressed
+        ppu.catch_up(4 * (22 + 10));
+        ppu.write(0x2121, 0x05);
+        ppu.write(0x2122, 0x34);
+        ppu.write(0x2122, 0x12);
+        assert_eq!(ppu.color.cgram[2], 0x1234);
+        assert_eq!(ppu.color.cgram[5], 0x0000);
+
+        // The backdrop fetches the color 0
+        ppu.catch_up(4 * (22 + 20));
+        ppu.write(0x2122, 0x78);
+        ppu.write(0x2122, 0x56);
+        assert_eq!(ppu.color.cgram[0], 0x5678);
+
+        // Before the first visible dot the addressed color is written
+        ppu.catch_up(4 * 10);
+        ppu.write(0x2121, 0x05);
+        ppu.write(0x2122, 0x34);
+        ppu.write(0x2122, 0x12);
+        assert_eq!(ppu.color.cgram[5], 0x1234);
+    }
+
+    #[test]
+    fn cgram_writes_follow_the_dot_renderer() {
+        let mut ppu = ppu_with_large_tiles();
+        ppu.access_restrictions = true;
+        ppu.dot_renderer = true;
+        ppu.vram[0x400] = 0x0001;
+        ppu.counters.vertical_counter = 1;
+
+        // Column 3 is drawn with the color 1 before it changes
+        ppu.catch_up(4 * (22 + 3));
+        ppu.write(0x2121, 0x05);
+        ppu.write(0x2122, 0xE0);
+        ppu.write(0x2122, 0x03);
+        assert_eq!(ppu.color.cgram[1], 0x03E0);
+        ppu.draw_pixels_until(WIDTH);
+
+        let line = line(&ppu, 1);
+        assert_eq!(line[3], [255, 0, 0]);
+        assert_eq!(line[4], [0, 255, 0]);
     }
 }
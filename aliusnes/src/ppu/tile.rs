@@ -9,18 +9,45 @@ bitfield! {
 }
 
 impl TileMapEntry {
-    pub fn adjust_coords_to_flipping(&self, x: usize, y: usize) -> (usize, usize) {
-        let h_shift = if self.flip_horizontal() {
-            x % 8
+    /// Flips the coordinates of a pixel inside a tile of the given size
+    pub fn adjust_coords_to_flipping(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> (usize, usize) {
+        let col = if self.flip_horizontal() {
+            width - 1 - x
         } else {
-            7 - (x % 8)
+            x
         };
-        let v_shift = if self.flip_vertical() {
-            7 - (y % 8)
+        let row = if self.flip_vertical() {
+            height - 1 - y
         } else {
-            y % 8
+            y
         };
-        (h_shift, v_shift)
+        (col, row)
+    }
+}
+
+bitfield! {
+    /// BG3 tilemap entry in offset-per-tile modes
+    pub struct OffsetPerTileEntry(pub u16) {
+        pub offset: u16 @ 0..=9,
+        bg1_enabled: bool @ 13,
+        bg2_enabled: bool @ 14,
+        pub vertical: bool @ 15,
+    }
+}
+
+impl OffsetPerTileEntry {
+    pub fn applies_to(&self, bg_idx: usize) -> bool {
+        match bg_idx {
+            0 => self.bg1_enabled(),
+            1 => self.bg2_enabled(),
+            _ => false,
+        }
     }
 }
 
@@ -47,50 +47,74 @@ impl Vram {
         }
     }
 
-    pub fn vm_addl(&mut self, data: u8) {
+    /// Loads the word at the current address into the read latch,
+    /// reads return the latch content before the prefetch
+    fn prefetch(&mut self, accessible: bool) {
+        self.read_latch = if accessible {
+            self[self.remap_vm_addr()]
+        } else {
+            0
+        };
+    }
+
+    pub fn vm_addl(&mut self, data: u8, accessible: bool) {
         self.vm_addr.set_low_byte(data);
-        self.read_latch = self[self.remap_vm_addr()];
+        self.prefetch(accessible);
     }
 
-    pub fn vm_addh(&mut self, data: u8) {
+    pub fn vm_addh(&mut self, data: u8, accessible: bool) {
         self.vm_addr.set_high_byte(data);
-        self.read_latch = self[self.remap_vm_addr()];
+        self.prefetch(accessible);
     }
 
-    pub fn vm_addl_write(&mut self, data: u8) {
-        let addr = self.remap_vm_addr();
-        self[addr].set_low_byte(data);
+    pub fn vm_addl_write(&mut self, data: u8, accessible: bool) {
+        if accessible {
+            let addr = self.remap_vm_addr();
+            self[addr].set_low_byte(data);
+        }
         if !self.video_port_control.increment_on_high_byte_access() {
             self.vm_addr += self.get_increment_amount();
         }
     }
 
-    pub fn vm_addh_write(&mut self, data: u8) {
-        let addr = self.remap_vm_addr();
-        self[addr].set_high_byte(data);
+    pub fn vm_addh_write(&mut self, data: u8, accessible: bool) {
+        if accessible {
+            let addr = self.remap_vm_addr();
+            self[addr].set_high_byte(data);
+        }
         if self.video_port_control.increment_on_high_byte_access() {
             self.vm_addr += self.get_increment_amount();
         }
     }
 
-    pub fn vm_addl_read(&mut self) -> u8 {
+    pub fn vm_addl_read(&mut self, accessible: bool) -> u8 {
         let result = self.read_latch.low_byte();
         if !self.video_port_control.increment_on_high_byte_access() {
-            self.read_latch = self[self.remap_vm_addr()];
+            self.prefetch(accessible);
             self.vm_addr += self.get_increment_amount();
         }
         result
     }
 
-    pub fn vm_addh_read(&mut self) -> u8 {
+    pub fn vm_addh_read(&mut self, accessible: bool) -> u8 {
         let result = self.read_latch.high_byte();
         if self.video_port_control.increment_on_high_byte_access() {
-            self.read_latch = self[self.remap_vm_addr()];
+            self.prefetch(accessible);
             self.vm_addr += self.get_increment_amount();
         }
         result
     }
 
+    /// In mode 7 the low bytes of the first 16K words hold a 128x128 tilemap
+    pub fn mode7_tile(&self, tile_x: usize, tile_y: usize) -> u8 {
+        self[(tile_y << 7) | tile_x].low_byte()
+    }
+
+    /// In mode 7 the high bytes hold 256 8x8 tiles with one byte per pixel
+    pub fn mode7_pixel(&self, tile: u8, x: usize, y: usize) -> u8 {
+        self[(usize::from(tile) << 6) | (y << 3) | x].high_byte()
+    }
+
     pub fn planes<T: BitPlane>(&self, addr: usize) -> [u8; T::PLANES] {
         let mut planes = [0; T::PLANES];
         for index in 0..(T::PLANES >> 1) {
@@ -115,3 +139,47 @@ impl std::ops::IndexMut<usize> for Vram {
         &mut self.ram[index & 0x7FFF]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_return_the_prefetched_word() {
+        let mut vram = Vram::new();
+        vram.video_port_control = VideoPortControl(0x80);
+        vram[0x1000] = 0x1234;
+
+        vram.vm_addl(0x00, true);
+        vram.vm_addh(0x10, true);
+        vram[0x1000] = 0xFFFF;
+
+        assert_eq!(vram.vm_addl_read(true), 0x34);
+        assert_eq!(vram.vm_addh_read(true), 0x12);
+    }
+
+    #[test]
+    fn prefetch_during_active_display_reads_zero() {
+        let mut vram = Vram::new();
+        vram.video_port_control = VideoPortControl(0x80);
+        vram[0] = 0x1234;
+
+        vram.vm_addl(0x00, false);
+        assert_eq!(vram.vm_addl_read(true), 0x00);
+        assert_eq!(vram.vm_addh_read(true), 0x00);
+    }
+
+    #[test]
+    fn writes_during_active_display_are_dropped_but_increment() {
+        let mut vram = Vram::new();
+        vram.video_port_control = VideoPortControl(0x80);
+
+        vram.vm_addl_write(0x34, false);
+        vram.vm_addh_write(0x12, false);
+        vram.vm_addl_write(0x78, true);
+        vram.vm_addh_write(0x56, true);
+
+        assert_eq!(vram[0], 0x0000);
+        assert_eq!(vram[1], 0x5678);
+    }
+}
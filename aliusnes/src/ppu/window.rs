@@ -0,0 +1,181 @@
+use crate::ppu::WIDTH;
+
+pub const OBJ_LAYER: usize = 4;
+pub const COLOR_LAYER: usize = 5;
+
+bitfield! {
+    #[derive(Clone, Copy)]
+    struct WindowMask(u8) {
+        w1_inverted: bool @ 0,
+        w1_enabled: bool @ 1,
+        w2_inverted: bool @ 2,
+        w2_enabled: bool @ 3,
+    }
+}
+
+/// Window settings for BG1-4, OBJ and the color window
+pub(super) struct Window {
+    w1_left: u8,
+    w1_right: u8,
+    w2_left: u8,
+    w2_right: u8,
+    masks: [WindowMask; 6],
+    logic: [u8; 6],
+    pub main_screen_disable: u8,
+    pub sub_screen_disable: u8,
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Self {
+            w1_left: 0,
+            w1_right: 0,
+            w2_left: 0,
+            w2_right: 0,
+            masks: [WindowMask(0); 6],
+            logic: [0; 6],
+            main_screen_disable: 0,
+            sub_screen_disable: 0,
+        }
+    }
+
+    /// W12SEL, W34SEL and WOBJSEL hold the settings of two layers each
+    pub fn set_mask_settings(&mut self, first_layer: usize, data: u8) {
+        self.masks[first_layer] = WindowMask(data & 0xF);
+        self.masks[first_layer + 1] = WindowMask(data >> 4);
+    }
+
+    pub fn set_bg_logic(&mut self, data: u8) {
+        for idx in 0..4 {
+            self.logic[idx] = (data >> (idx * 2)) & 3;
+        }
+    }
+
+    pub fn set_obj_color_logic(&mut self, data: u8) {
+        self.logic[OBJ_LAYER] = data & 3;
+        self.logic[COLOR_LAYER] = (data >> 2) & 3;
+    }
+
+    pub fn set_w1_left(&mut self, data: u8) {
+        self.w1_left = data;
+    }
+
+    pub fn set_w1_right(&mut self, data: u8) {
+        self.w1_right = data;
+    }
+
+    pub fn set_w2_left(&mut self, data: u8) {
+        self.w2_left = data;
+    }
+
+    pub fn set_w2_right(&mut self, data: u8) {
+        self.w2_right = data;
+    }
+
+    /// Returns for each pixel if it is inside the window area of `layer`,
+    /// combining both windows with the layer mask logic
+    pub fn layer_area(&self, layer: usize) -> [bool; WIDTH] {
+        let mask = self.masks[layer];
+        let mut area = [false; WIDTH];
+        if !mask.w1_enabled() && !mask.w2_enabled() {
+            return area;
+        }
+
+        for (x, inside) in area.iter_mut().enumerate() {
+            let x = x as u8;
+            let w1 = (self.w1_left..=self.w1_right).contains(&x) ^ mask.w1_inverted();
+            let w2 = (self.w2_left..=self.w2_right).contains(&x) ^ mask.w2_inverted();
+
+            *inside = match (mask.w1_enabled(), mask.w2_enabled()) {
+                (true, false) => w1,
+                (false, true) => w2,
+                _ => match self.logic[layer] {
+                    0 => w1 | w2,
+                    1 => w1 & w2,
+                    2 => w1 ^ w2,
+                    _ => !(w1 ^ w2),
+                },
+            };
+        }
+        area
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Window 1 covers the pixels 10 to 20 and window 2 the pixels 15 to 30
+    fn window(bg1_mask: u8, bg1_logic: u8) -> Window {
+        let mut window = Window::new();
+        window.set_w1_left(10);
+        window.set_w1_right(20);
+        window.set_w2_left(15);
+        window.set_w2_right(30);
+        window.set_mask_settings(0, bg1_mask);
+        window.set_bg_logic(bg1_logic);
+        window
+    }
+
+    fn inside(area: &[bool; WIDTH]) -> Vec<usize> {
+        (0..WIDTH).filter(|&x| area[x]).collect()
+    }
+
+    #[test]
+    fn windows_cover_their_range_or_its_outside() {
+        assert_eq!(
+            inside(&window(0x02, 0).layer_area(0)),
+            (10..=20).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            inside(&window(0x08, 0).layer_area(0)),
+            (15..=30).collect::<Vec<_>>()
+        );
+
+        let w1_inverted = window(0x03, 0).layer_area(0);
+        assert!(w1_inverted[9] && !w1_inverted[10] && !w1_inverted[20] && w1_inverted[21]);
+        let w2_inverted = window(0x0C, 0).layer_area(0);
+        assert!(w2_inverted[14] && !w2_inverted[15] && !w2_inverted[30] && w2_inverted[31]);
+
+        // Disabled windows never mask the layer, even with inversion
+        assert_eq!(window(0x05, 0).layer_area(0), [false; WIDTH]);
+    }
+
+    #[test]
+    fn logic_combines_both_windows() {
+        let or = (10..=30).collect::<Vec<_>>();
+        let and = (15..=20).collect::<Vec<_>>();
+        let xor = (10..15).chain(21..=30).collect::<Vec<_>>();
+        let xnor = (0..10).chain(15..=20).chain(31..WIDTH).collect::<Vec<_>>();
+
+        for (logic, expected) in [(0, or), (1, and), (2, xor), (3, xnor)] {
+            assert_eq!(inside(&window(0x0A, logic).layer_area(0)), expected);
+        }
+    }
+
+    #[test]
+    fn left_past_right_makes_an_empty_window() {
+        let mut window = window(0x02, 0);
+        window.set_w1_left(20);
+        window.set_w1_right(10);
+        assert_eq!(window.layer_area(0), [false; WIDTH]);
+
+        window.set_mask_settings(0, 0x03);
+        assert_eq!(window.layer_area(0), [true; WIDTH]);
+    }
+
+    #[test]
+    fn each_layer_has_its_own_mask_and_logic() {
+        let mut window = window(0x20, 0);
+        window.set_mask_settings(OBJ_LAYER, 0xA0);
+        window.set_obj_color_logic(0x04);
+
+        assert_eq!(window.layer_area(0), [false; WIDTH]);
+        assert_eq!(inside(&window.layer_area(1)), (10..=20).collect::<Vec<_>>());
+        assert_eq!(window.layer_area(OBJ_LAYER), [false; WIDTH]);
+        assert_eq!(
+            inside(&window.layer_area(COLOR_LAYER)),
+            (15..=20).collect::<Vec<_>>()
+        );
+    }
+}
@@ -7,15 +7,31 @@ pub enum PpuEvent {
     NewScanline,
 }
 
+#[derive(Clone, Copy)]
+pub enum DmaEvent {
+    HdmaInit,
+    HdmaTransfer,
+}
+
+#[derive(Clone, Copy)]
+pub enum JoypadEvent {
+    AutoReadStart,
+    AutoReadEnd,
+}
+
 #[derive(Clone, Copy)]
 pub enum Event {
     Ppu(PpuEvent),
+    Dma(DmaEvent),
+    Joypad(JoypadEvent),
 }
 
 impl Event {
     fn index(self) -> usize {
         match self {
+            Event::Dma(_) => 0,
             Event::Ppu(_) => 1,
+            Event::Joypad(_) => 2,
         }
     }
 }
@@ -77,12 +93,12 @@ impl Scheduler {
         }
 
         let id = self.next_event_id;
+        let slot = &mut self.events[id];
+        let popped = (slot.event, mem::replace(&mut slot.time, u64::MAX));
 
         self.find_next_event();
 
-        let slot = &mut self.events[id];
-
-        Some((slot.event, mem::replace(&mut slot.time, u64::MAX)))
+        Some(popped)
     }
 
     pub(crate) fn tick(&mut self, ticks: u64) {
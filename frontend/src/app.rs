@@ -1,12 +1,14 @@
 use aliusnes::cart::Cart;
+use aliusnes::emu::{Buttons, FB_HEIGHT, FB_WIDTH};
 use eframe::CreationContext;
-use eframe::egui::{self, Color32, ColorImage};
+use eframe::egui::{self, Color32, ColorImage, Key};
 
 use crate::emu_state::{EmuState, Message};
 
 pub struct App {
     emu_state: EmuState,
     playing: bool,
+    buttons: u16,
     texture: egui::TextureHandle,
 }
 
@@ -16,17 +18,45 @@ impl App {
         Self {
             emu_state: EmuState::new(cart),
             playing: true,
+            buttons: 0,
             texture: cc.egui_ctx.load_texture(
                 "Framebuffer",
-                egui::ColorImage::filled([512, 478], egui::Color32::TRANSPARENT),
+                egui::ColorImage::filled([FB_WIDTH, FB_HEIGHT], egui::Color32::TRANSPARENT),
                 Default::default(),
             ),
         }
     }
 }
 
+impl App {
+    /// Maps the keyboard to the controller on the first port
+    fn read_buttons(&mut self, ui: &egui::Ui) {
+        let buttons = ui.input(|input| {
+            Buttons(0)
+                .with_b(input.key_down(Key::Z))
+                .with_a(input.key_down(Key::X))
+                .with_y(input.key_down(Key::A))
+                .with_x(input.key_down(Key::S))
+                .with_l(input.key_down(Key::Q))
+                .with_r(input.key_down(Key::W))
+                .with_select(input.key_down(Key::Backspace))
+                .with_start(input.key_down(Key::Enter))
+                .with_up(input.key_down(Key::ArrowUp))
+                .with_down(input.key_down(Key::ArrowDown))
+                .with_left(input.key_down(Key::ArrowLeft))
+                .with_right(input.key_down(Key::ArrowRight))
+        });
+        if buttons.0 != self.buttons {
+            self.buttons = buttons.0;
+            self.emu_state.send_message(Message::Buttons(buttons));
+        }
+    }
+}
+
 impl eframe::App for App {
     fn ui(&mut self, ui: &mut egui::Ui, _: &mut eframe::Frame) {
+        self.read_buttons(ui);
+
         egui::Panel::left("my_left_panel").show_inside(ui, |ui| {
             ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
                 if ui.button("Step").clicked() {
@@ -60,7 +90,7 @@ impl eframe::App for App {
                 }
                 self.texture.set(image, egui::TextureOptions::default());
             };
-            let size = egui::Vec2::new(512.0, 478.0);
+            let size = egui::Vec2::new(FB_WIDTH as f32, FB_HEIGHT as f32);
             let (whole_rect, _) =
                 ui.allocate_exact_size(size, egui::Sense::focusable_noninteractive());
             egui::Image::new((self.texture.id(), self.texture.size_vec2()))
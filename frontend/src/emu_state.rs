@@ -2,18 +2,19 @@ use std::sync::mpsc::{Receiver, Sender, channel};
 use std::thread;
 
 use aliusnes::cart::Cart;
-use aliusnes::emu::Emu;
+use aliusnes::emu::{Buttons, Emu};
 
 pub enum Message {
     Pause,
     Play,
     Step,
+    Buttons(Buttons),
 }
 
 pub struct Frame {
     pub width: usize,
     pub height: usize,
-    pub buffer: [[u8; 3]; 61184],
+    pub buffer: Vec<[u8; 3]>,
 }
 
 #[allow(dead_code)]
@@ -50,18 +51,18 @@ impl EmuState {
                         paused = true;
                         emu.step();
                     },
+                    Message::Buttons(buttons) => emu.set_buttons(0, buttons),
                 }
             }
 
             if !paused {
                 emu.run_frame();
             }
-            let mut frame = Frame {
+            let frame = Frame {
                 width: emu.frame_width(),
                 height: emu.frame_height(),
-                buffer: [[0; 3]; 61184],
+                buffer: emu.frame().to_vec(),
             };
-            frame.buffer.copy_from_slice(emu.frame());
 
             let _ = frame_tx.push(frame);
         }